@@ -1,42 +1,813 @@
-use rusqlite::{Connection, Result as DBResult};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result as DBResult};
+use sha2::{Digest, Sha256};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, thread};
+use uuid::Uuid;
+
+mod servers_repo;
+
+const QUIET: u8 = 0;
+const NORMAL: u8 = 1;
+const VERBOSE: u8 = 2;
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+// Set by `serve --accept-key-change`: adopt a MASTER_KEY that differs from the stored one.
+static ACCEPT_KEY_CHANGE: AtomicBool = AtomicBool::new(false);
+// Set by `serve --repl`: read admin commands from stdin while serving.
+static REPL: AtomicBool = AtomicBool::new(false);
+
+// Informational output, hidden by --quiet.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= NORMAL {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Debug output, only shown with --verbose.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= VERBOSE {
+            println!($($arg)*);
+        }
+    };
+}
+
+const DB_FILE: &str = "master_node.db";
+const STORAGE_DIR: &str = "storage";
+const SCHEMA_VERSION: i64 = 3;
+const DEFAULT_MAX_CONNECTIONS: usize = 100;
+const DEFAULT_REKEY_GRACE_SECS: u64 = 3600;
+const EVENT_BUFFER: usize = 64;
+const DEFAULT_EVENTS_LIMIT: usize = 100;
+const MIN_KEY_LEN: usize = 16;
+const DEFAULT_DB_BUSY_TIMEOUT_MS: u64 = 5000;
+const DB_BUSY_RETRIES: u32 = 3;
+const DEFAULT_TAIL_LINES: usize = 20;
+const MAX_TAIL_LINES: usize = 10_000;
+// Ends each reply on a SESSION connection. Reply lines that start with a dot get
+// another one in front, as in SMTP, so none of them can be mistaken for it.
+const SESSION_END: &str = ".";
+const LEAVE_ATTEMPTS: u32 = 3;
+const DEFAULT_MAX_HOOKS: usize = 4;
+const DEFAULT_SLAVE_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 30;
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_KEEPALIVE_IDLE_SECS: u64 = 60;
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_KEEPALIVE_RETRIES: u32 = 5;
+
+// Settings already warned about, so one read per request doesn't repeat the warning.
+static INVALID_SETTINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Reads a setting from the environment, falling back to `default` when it is unset.
+// A value that doesn't parse also falls back, with a warning (once per setting) and
+// "invalid" as its source, so a typo is never mistaken for the default.
+fn env_setting<T: FromStr>(name: &str, default: T) -> (T, &'static str)
+where
+    T::Err: std::fmt::Display,
+{
+    let Ok(value) = env::var(name) else {
+        return (default, "default");
+    };
+    match value.parse::<T>() {
+        Ok(value) => (value, "env"),
+        Err(e) => {
+            let mut warned = INVALID_SETTINGS.lock().unwrap();
+            if !warned.iter().any(|known| known == name) {
+                warned.push(name.to_string());
+                eprintln!(
+                    "⚠️ Ignoring {}={:?}: {}; using the default.",
+                    name, value, e
+                );
+            }
+            (default, "invalid")
+        }
+    }
+}
+
+// Expands `${VAR}` and `${VAR:-default}` in a config value using `lookup`. An unset
+// variable without a default is an error, so a typo can't quietly become "".
+fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unterminated '${{' in '{}'", value));
+        };
+        let expression = &rest[start + 2..start + end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+        match lookup(name).or_else(|| default.map(str::to_string)) {
+            Some(expanded) => out.push_str(&expanded),
+            None => return Err(format!("environment variable {} is not set", name)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Matches `name` against a pattern where `*` is any run of characters and `?` any
+// single one. A pattern without wildcards is a prefix, so `logs/` lists a directory.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return name.starts_with(pattern);
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was seen, and how much of the name it has swallowed.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, consumed)) => {
+                    p = star + 1;
+                    n = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Reads the first line of a reply, skipping the optional connect banner.
+fn read_reply<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    loop {
+        let start = line.len();
+        let read = reader.read_line(line)?;
+        if read > 0 && line[start..].starts_with("BANNER ") {
+            line.truncate(start);
+            continue;
+        }
+        return Ok(read);
+    }
+}
+
+// Splits `host:port`, taking the brackets off an IPv6 host such as `[::1]:8777`.
+fn split_host_port(address: &str) -> Option<(&str, &str)> {
+    let (host, port) = address.rsplit_once(':')?;
+    Some((host.trim_matches(['[', ']']), port))
+}
+
+// One spelling per node: an IPv4 peer reached over a dual-stack socket shows up as
+// `::ffff:a.b.c.d`, which is stored and looked up as plain `a.b.c.d`.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(address.ip()), address.port())
+}
+
+// Levenshtein distance: the single-character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// How each handled request is written to stdout, picked with REQUEST_LOG.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RequestLog {
+    Off,
+    Plain,
+    Json,
+}
+
+impl FromStr for RequestLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(RequestLog::Off),
+            "plain" => Ok(RequestLog::Plain),
+            "json" => Ok(RequestLog::Json),
+            other => Err(format!("unknown request log format '{}'", other)),
+        }
+    }
+}
+
+impl RequestLog {
+    fn name(self) -> &'static str {
+        match self {
+            RequestLog::Off => "off",
+            RequestLog::Plain => "plain",
+            RequestLog::Json => "json",
+        }
+    }
+
+    // One line per request. Only the verb is logged, never the arguments, which
+    // can carry the master key. `code` is None for replies without a status code.
+    fn line(
+        self,
+        method: &str,
+        source: SocketAddr,
+        code: Option<u16>,
+        duration: Duration,
+    ) -> Option<String> {
+        let code_text = code.map_or("-".to_string(), |code| code.to_string());
+        match self {
+            RequestLog::Off => None,
+            RequestLog::Plain => Some(format!(
+                "{} {} {} {}us",
+                source,
+                method,
+                code_text,
+                duration.as_micros()
+            )),
+            RequestLog::Json => Some(format!(
+                "{{\"ts\":{},\"method\":{},\"source\":\"{}\",\"code\":{},\"duration_us\":{}}}",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                json_string(method),
+                source,
+                if code.is_some() { &code_text } else { "null" },
+                duration.as_micros()
+            )),
+        }
+    }
+}
+
+// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Decodes `%XX` escapes and `+` in a URL path or query value.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (byte, _) => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The status code a reply starts with, e.g. 200 for "200 FLUSHED ...".
+fn reply_code(lines: &[String]) -> Option<u16> {
+    lines
+        .first()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+        .filter(|code| (100..600).contains(code))
+}
 
 struct Server {
-    master_key: String,
     listener: TcpListener,
     database: Arc<Mutex<Connection>>,
+    max_connections: usize,
+    connections: Arc<ConnStats>,
+    is_master: bool,
+    events: Arc<EventBus>,
+    storage: Arc<dyn Storage>,
+    // Sent to every client before its command when BANNER is on (master only).
+    banner: Option<String>,
+    registry: Arc<Registry>,
+}
+
+// Connection accounting reported by CONNSTATS. A `live` count that stays above zero
+// on an idle server points at a handler that never returns. Subscribers (every slave
+// keeps one to its master) are counted apart so they don't use up MAX_CONNECTIONS.
+#[derive(Default)]
+struct ConnStats {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    handled: AtomicUsize,
+    subscribers: AtomicUsize,
+}
+
+impl ConnStats {
+    // Counts a new connection and returns how many were already live.
+    fn enter(&self) -> usize {
+        let previous = self.live.fetch_add(1, Ordering::SeqCst);
+        self.peak.fetch_max(previous + 1, Ordering::SeqCst);
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        previous
+    }
+}
+
+// Caps how fast the listeners hand out connections, whatever IPs they come from.
+// Holds up to `burst` tokens and refills `rate` per second.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    // Takes a token, returning how long to wait first if none was left.
+    fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// Releases a connection slot when the handler thread finishes, even on panic.
+struct ConnectionGuard(Arc<ConnStats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Moves a connection from `live` to `subscribers` while it follows the event stream.
+// Dropped before the ConnectionGuard, which then takes the connection off `live`.
+struct SubscriberGuard<'a>(&'a ConnStats);
+
+impl<'a> SubscriberGuard<'a> {
+    fn new(stats: &'a ConnStats) -> Self {
+        stats.subscribers.fetch_add(1, Ordering::SeqCst);
+        stats.live.fetch_sub(1, Ordering::SeqCst);
+        SubscriberGuard(stats)
+    }
+}
+
+impl Drop for SubscriberGuard<'_> {
+    fn drop(&mut self) {
+        self.0.live.fetch_add(1, Ordering::SeqCst);
+        self.0.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Where a node keeps its files. FILES, USAGE and DELETEMANY are the file commands,
+// and the HTTP index downloads, so a backend lists, sizes, reads and removes.
+trait Storage: Send + Sync {
+    fn list(&self) -> io::Result<Vec<String>>;
+    // Contents of a file by a name `list` returned.
+    fn read(&self, name: &str) -> io::Result<Vec<u8>>;
+    // Number of files held and their total size in bytes.
+    fn usage(&self) -> io::Result<(u64, u64)>;
+    // Removes a file by a name `list` returned.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+struct FsStorage {
+    root: PathBuf,
+}
+
+impl Storage for FsStorage {
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.root)?.flatten() {
+            let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
+            if !is_dir {
+                files.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(files)
+    }
+
+    fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(name))
+    }
+
+    fn usage(&self) -> io::Result<(u64, u64)> {
+        let (mut files, mut bytes) = (0, 0);
+        for entry in fs::read_dir(&self.root)?.flatten() {
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files += 1;
+                bytes += metadata.len();
+            }
+        }
+        Ok((files, bytes))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.root.join(name))
+    }
+}
+
+// What a key may do. The swarm key is always Admin; other keys get a role from
+// the KEYS_FILE.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Role {
+    ReadOnly,
+    Admin,
+}
+
+impl Role {
+    // Parses KEYS_FILE contents: one `<sha256 of key> <read|admin>` pair per line,
+    // blank lines and `#` comments ignored.
+    fn lookup(keys: &str, key_hash: &str) -> Option<Role> {
+        keys.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .find(|(hash, _)| *hash == key_hash)
+            .and_then(|(_, role)| match role.trim() {
+                "read" => Some(Role::ReadOnly),
+                "admin" => Some(Role::Admin),
+                _ => None,
+            })
+    }
+}
+
+// Fans server events out to SUBSCRIBE connections. Each subscriber gets a bounded
+// buffer; one that falls behind is dropped instead of blocking the publisher.
+struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<String>>>,
+}
+
+impl EventBus {
+    fn new() -> Self {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn publish(&self, event: String) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Dropping slow event subscriber");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+// The words after the verb, split the way clients send them.
+fn arguments(line: &str) -> Vec<&str> {
+    line.split(' ').skip(1).collect()
+}
+
+// The reply to a known verb with the wrong arguments.
+fn invalid(verb: &str) -> Response {
+    Response::line(&format!("400 BAD_REQUEST wrong arguments for {}", verb))
+}
+
+// `[--since <ts>] [--limit N]` in any order; None if anything else is present.
+fn event_filters(flags: &[&str]) -> Option<(u64, usize)> {
+    let mut since = 0;
+    let mut limit = DEFAULT_EVENTS_LIMIT;
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--since", value] => since = value.parse().ok()?,
+            ["--limit", value] => limit = value.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some((since, limit))
+}
+
+type Handler = Box<dyn Fn(&str, &Ctx) -> Result<Response, Box<dyn Error>> + Send + Sync>;
+
+// Maps each verb to the handler that answers it. Each handler parses its own
+// arguments, so adding a command (built in, a test double, an optional feature) is
+// one `register` call.
+struct Registry {
+    handlers: Vec<(&'static str, Handler)>,
+    // Extra spellings from COMMAND_ALIASES, rewritten to their verb before dispatch.
+    aliases: Vec<(String, &'static str)>,
+}
+
+impl Registry {
+    fn builtin() -> Self {
+        let mut registry = Registry {
+            handlers: Vec::new(),
+            aliases: Vec::new(),
+        };
+        registry.register("JOIN", Server::join_command);
+        registry.register("LEAVE", Server::leave_command);
+        registry.register("LIST", Server::list_command);
+        registry.register("FILES", Server::files_command);
+        registry.register("REKEY", Server::rekey_command);
+        registry.register("HEALTH", Server::health_command);
+        registry.register("COMMANDS", Server::commands_command);
+        registry.register("SUBSCRIBE", Server::subscribe_command);
+        registry.register("USAGE", Server::usage_command);
+        registry.register("EVICT", Server::evict_command);
+        registry.register("CONNSTATS", Server::conn_stats_command);
+        registry.register("EVENTS", Server::events_command);
+        registry.register("FLUSH", Server::flush_command);
+        registry.register("MASTER", Server::master_command);
+        registry.register("DELETEMANY", Server::delete_many_command);
+        registry.register("TAILLOG", Server::tail_log_command);
+        registry.register("COUNT", Server::count_command);
+        registry.register("SESSION", Server::session_command);
+        registry
+    }
+
+    // Adds `verb`, or replaces the handler it already had.
+    fn register<F>(&mut self, verb: &'static str, handler: F)
+    where
+        F: Fn(&str, &Ctx) -> Result<Response, Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.handlers.retain(|(known, _)| *known != verb);
+        self.handlers.push((verb, Box::new(handler)));
+    }
+
+    // The built-in verbs plus the aliases in COMMAND_ALIASES, a comma-separated list
+    // of `alias=VERB` pairs such as `ls=LIST,hc=HEALTH`. Bad pairs are skipped.
+    fn configured() -> Self {
+        let mut registry = Self::builtin();
+        let aliases = env_setting("COMMAND_ALIASES", String::new()).0;
+        for pair in aliases.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let result = match pair.split_once('=') {
+                Some((alias, verb)) => registry.alias(alias.trim(), verb.trim()),
+                None => Err(format!("expected alias=VERB, got '{}'", pair.trim())),
+            };
+            if let Err(e) = result {
+                eprintln!("⚠️ Ignoring command alias: {}", e);
+            }
+        }
+        registry
+    }
+
+    // Makes `alias` another name for the registered `verb`. An alias cannot shadow a verb.
+    fn alias(&mut self, alias: &str, verb: &str) -> Result<(), String> {
+        if alias.is_empty() || alias.contains(char::is_whitespace) {
+            return Err(format!("'{}' is not a valid alias", alias));
+        }
+        if self.verbs().any(|known| known == alias) {
+            return Err(format!("'{}' is already a command", alias));
+        }
+        let Some(verb) = self.verbs().find(|known| *known == verb) else {
+            return Err(format!("'{}' is not a command", verb));
+        };
+        self.aliases.retain(|(known, _)| known != alias);
+        self.aliases.push((alias.to_string(), verb));
+        Ok(())
+    }
+
+    fn verbs(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.iter().map(|(verb, _)| *verb)
+    }
+
+    // Unregistered verbs are reported as unknown, with a suggestion if one is close.
+    fn dispatch(&self, line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let resolved;
+        let (verb, line) = match self.aliases.iter().find(|(alias, _)| alias == verb) {
+            Some((_, target)) => {
+                resolved = format!("{} {}", target, rest);
+                (*target, resolved.trim_end())
+            }
+            None => (verb, line),
+        };
+        match self.handlers.iter().find(|(known, _)| *known == verb) {
+            Some((_, handler)) => handler(line, ctx),
+            None => Server::unknown_command(verb, ctx),
+        }
+    }
+}
+
+// Everything a command needs besides the socket it arrived on.
+struct Ctx<'a> {
+    db: &'a Mutex<Connection>,
+    address: SocketAddr,
+    // The address the client reached us on.
+    local_address: SocketAddr,
+    is_master: bool,
+    events: &'a EventBus,
+    storage: &'a dyn Storage,
+    connections: &'a ConnStats,
+    registry: &'a Registry,
+}
+
+// Lines written back to the client, in order, optionally followed by a stream of
+// lines that is forwarded as it arrives: events for SUBSCRIBE, file names for LIST.
+struct Response {
+    lines: Vec<String>,
+    stream: Option<Receiver<String>>,
+    // The stream never ends on its own (SUBSCRIBE), so the connection is not held
+    // against MAX_CONNECTIONS while it runs.
+    subscription: bool,
+}
+
+impl Response {
+    fn new(lines: Vec<String>) -> Self {
+        Response {
+            lines,
+            stream: None,
+            subscription: false,
+        }
+    }
+
+    fn line(line: &str) -> Self {
+        Response::new(vec![line.to_string()])
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // Sent for file downloads so clients can revalidate with If-None-Match.
+    etag: Option<String>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status,
+            content_type,
+            body,
+            etag: None,
+        }
+    }
 }
 
 struct Config {
     master_ip_address: String,
     master_port: u32,
     slave_port: u32,
+    master_key: Option<String>,
+    swarm_id: Option<String>,
+}
+
+impl Config {
+    // A slave that cannot resolve its master's address would start and then never
+    // reach it, so refuse to serve instead. Host names are fine if they resolve.
+    fn validate(&self) -> Result<(), String> {
+        if self.master_ip_address.is_empty() {
+            return Err("config.txt has no master_ip_address.".to_string());
+        }
+        if self.master_port == 0 || self.master_port > u16::MAX as u32 {
+            return Err(format!(
+                "config.txt master_port {} is not a valid port.",
+                self.master_port
+            ));
+        }
+        if let Err(e) = (self.master_ip_address.as_str(), self.master_port as u16).to_socket_addrs()
+        {
+            return Err(format!(
+                "config.txt master_ip_address '{}' does not resolve: {}",
+                self.master_ip_address, e
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Server {
     fn connect() -> Result<Self, Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => Self::connect_slave(config),
-            None => Self::connect_master(),
+        let server = match Self::verify_config()? {
+            Some(config) => Self::connect_slave(config)?,
+            None => Self::connect_master()?,
+        };
+        if !Self::storage_writable() {
+            eprintln!(
+                "⚠️ Storage directory '{}' is missing or not writable.",
+                STORAGE_DIR
+            );
+        }
+        Ok(server)
+    }
+
+    fn storage_writable() -> bool {
+        let probe = Path::new(STORAGE_DIR).join(".write_probe");
+        match fs::write(&probe, b"") {
+            Ok(_) => fs::remove_file(&probe).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // BIND_ADDRESS picks the interface to listen on; loopback unless told otherwise.
+    fn bind_ip() -> (IpAddr, &'static str) {
+        env_setting("BIND_ADDRESS", IpAddr::from([127, 0, 0, 1]))
+    }
+
+    // Binds `port` on the configured interface, or any free port there if it is taken.
+    fn bind_listener(port: u32) -> io::Result<TcpListener> {
+        let ip = Self::bind_ip().0;
+        match TcpListener::bind((ip, u16::try_from(port).unwrap_or(0))) {
+            Ok(listener) => Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => {
+                eprintln!("⚠️ BIND_ADDRESS {} is not an address of this host.", ip);
+                Err(e)
+            }
+            Err(_) => TcpListener::bind((ip, 0)),
         }
     }
 
     fn connect_master() -> Result<Self, Box<dyn Error>> {
-        let listener = match TcpListener::bind("127.0.0.1:8777") {
-            Ok(listener) => listener,
-            Err(_) => TcpListener::bind("127.0.0.1:0").expect("Can't connect to any port."),
-        };
-        let database = Arc::new(Mutex::new(Self::build_db()?));
+        let listener = Self::bind_listener(8777)?;
         let master_key =
             std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
+        let database = Arc::new(Mutex::new(Self::build_db()?));
+        Self::seed_key(
+            &database.lock().unwrap(),
+            &master_key,
+            ACCEPT_KEY_CHANGE.load(Ordering::Relaxed),
+        )?;
+        let swarm_id = Self::seed_swarm_id(&database.lock().unwrap())?;
+        let banner = Self::banner(&swarm_id);
         let info = listener.local_addr()?;
-        println!(
+        Self::claim_swarm(&swarm_id, info)?;
+        info!(
             "👑 Master Listening at: http://{}:{}",
             info.ip(),
             info.port()
@@ -44,20 +815,33 @@ impl Server {
         Ok(Server {
             listener,
             database,
-            master_key,
+            max_connections: Self::max_connections(),
+            connections: Arc::new(ConnStats::default()),
+            is_master: true,
+            events: Arc::new(EventBus::new()),
+            storage: Arc::new(FsStorage {
+                root: PathBuf::from(STORAGE_DIR),
+            }),
+            banner,
+            registry: Arc::new(Registry::configured()),
         })
     }
 
     fn connect_slave(config: Config) -> Result<Self, Box<dyn Error>> {
-        let listener = match TcpListener::bind(format!("127.0.0.1:{}", config.slave_port)) {
-            Ok(listener) => listener,
-            Err(_) => TcpListener::bind("127.0.0.1:0").expect("Can't connect to any port."),
-        };
+        config.validate()?;
+        let listener = Self::bind_listener(config.slave_port)?;
         let database = Arc::new(Mutex::new(Self::build_db()?));
-        let master_key =
-            std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
+        let master_key = match config.master_key {
+            Some(key) => key,
+            None => std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set"),
+        };
+        Self::seed_key(
+            &database.lock().unwrap(),
+            &master_key,
+            ACCEPT_KEY_CHANGE.load(Ordering::Relaxed),
+        )?;
         let info = listener.local_addr()?;
-        println!(
+        info!(
             "🧑‍🌾 Listening as slave at: http://{}:{}",
             info.ip(),
             info.port()
@@ -65,79 +849,347 @@ impl Server {
         Ok(Server {
             listener,
             database,
-            master_key,
+            max_connections: Self::max_connections(),
+            connections: Arc::new(ConnStats::default()),
+            is_master: false,
+            events: Arc::new(EventBus::new()),
+            storage: Arc::new(FsStorage {
+                root: PathBuf::from(STORAGE_DIR),
+            }),
+            banner: None,
+            registry: Arc::new(Registry::configured()),
         })
     }
 
-    fn join(ip_addr: &str) -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(_) => {
-                println!("You are already part of a swarm. Type --help for more.")
+    // Refuses to start a second master for `swarm_id`, which happens when a database
+    // is copied or restored onto another node. Masters record their address in a lock
+    // file under SWARM_LOCK_DIR (the temp dir by default, so this only sees masters on
+    // the same host unless it points at a shared directory). A lock whose master no
+    // longer answers HEALTH is stale and taken over. Two masters starting at the same
+    // instant can both claim it, and SWARM_LOCK_DIR=off turns the check off.
+    fn claim_swarm(swarm_id: &str, address: SocketAddr) -> Result<(), Box<dyn Error>> {
+        let dir = env_setting("SWARM_LOCK_DIR", env::temp_dir().display().to_string()).0;
+        if dir == "off" {
+            return Ok(());
+        }
+        let lock = Path::new(&dir).join(format!("swarm-{}.lock", swarm_id));
+        if let Ok(holder) = fs::read_to_string(&lock) {
+            let holder = holder.trim();
+            if holder != address.to_string() && Self::answers_health(holder) {
+                return Err(format!(
+                    "swarm {} already has a master at {} (lock {})",
+                    swarm_id,
+                    holder,
+                    lock.display()
+                )
+                .into());
             }
-            None => {
-                let mut stream = TcpStream::connect(ip_addr)?;
-                let socket = stream.local_addr()?;
-                let master_key =
-                    std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
+            debug!(
+                "Taking over stale swarm lock {} from {}",
+                lock.display(),
+                holder
+            );
+        }
+        fs::write(&lock, format!("{}\n", address))?;
+        Ok(())
+    }
 
-                let command = format!("JOIN {}", master_key);
-                writeln!(stream, "{}", command)?;
+    fn answers_health(address: &str) -> bool {
+        let Some((ip, port)) = split_host_port(address) else {
+            return false;
+        };
+        let Ok(mut stream) = Self::connect_peer(ip, port) else {
+            return false;
+        };
+        let mut response = String::new();
+        writeln!(stream, "HEALTH").is_ok()
+            && read_reply(&mut BufReader::new(stream), &mut response).is_ok()
+            && response.trim() == "200 OK"
+    }
 
-                let mut response = String::new();
-                let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                let response = response.trim();
-                println!("Server: {}", response);
+    // Off unless BANNER=true, since clients that predate it would read the banner as
+    // the answer to their command.
+    fn banner(swarm_id: &str) -> Option<String> {
+        if !env_setting("BANNER", false).0 {
+            return None;
+        }
+        let motd = env_setting("MOTD", String::new()).0;
+        Some(
+            format!(
+                "BANNER swarm={} version={} motd={}",
+                swarm_id,
+                env!("CARGO_PKG_VERSION"),
+                motd
+            )
+            .trim_end()
+            .to_string(),
+        )
+    }
 
-                if response.contains("joined") {
-                    let parts: Vec<&str> = ip_addr.split(":").collect();
-                    let master_ip = parts[0].to_string();
-                    let master_port = parts[1].to_string();
-                    Self::create_config(master_ip, master_port, socket.port().to_string())?;
-                }
-            }
+    // ACCEPT_RATE=0 leaves accepting unthrottled. One bucket is shared by every
+    // listener, so the rate is a limit for the node as a whole.
+    fn accept_limit() -> Option<Arc<Mutex<TokenBucket>>> {
+        let rate = env_setting("ACCEPT_RATE", 0.0_f64).0;
+        (rate > 0.0).then(|| {
+            let burst = env_setting("ACCEPT_BURST", rate.ceil()).0.max(1.0);
+            Arc::new(Mutex::new(TokenBucket::new(rate, burst, Instant::now())))
+        })
+    }
+
+    // Waits for a token from `limit`. The lock is held only to take it, so listeners
+    // queue their waits rather than each other's sleeps.
+    fn pace_accept(limit: Option<&Mutex<TokenBucket>>) {
+        let Some(bucket) = limit else {
+            return;
+        };
+        let wait = bucket.lock().unwrap().take(Instant::now());
+        if !wait.is_zero() {
+            debug!("Accept rate exceeded, pausing {:?}", wait);
+            thread::sleep(wait);
         }
-        Ok(())
     }
 
-    fn leave() -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => {
-                let mut stream = TcpStream::connect(format!(
-                    "{}:{}",
-                    config.master_ip_address, config.master_port
-                ))?;
-                writeln!(stream, "LEAVE")?;
-                let mut response = String::new();
-                let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                let response = response.trim();
-                println!("Server: {}", response);
+    fn max_connections() -> usize {
+        env_setting("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS).0
+    }
 
-                if response.contains("left") {
-                    fs::remove_file("config.txt")?;
-                    println!("Config file has been deleted!")
-                }
-            }
-            None => {
-                eprintln!("You are not a part of any swarm! no action required.");
-            }
+    // How long an accepted client may take to send its next line. A client that sends
+    // nothing would otherwise hold one of MAX_CONNECTIONS forever. 0 waits forever.
+    fn idle_timeout() -> Option<Duration> {
+        match env_setting("IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT_SECS).0 {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
         }
-        Ok(())
     }
 
-    fn list() -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => {
+    // Read timeouts surface as WouldBlock on Unix and TimedOut on Windows.
+    fn is_timeout(e: &(dyn Error + 'static)) -> bool {
+        e.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+    }
+
+    // Connects to another node with SLAVE_TIMEOUT_SECS applied to connect, reads and
+    // writes, so a hung slave can't stall the caller indefinitely. `host` is an IP or a
+    // host name; each address it resolves to is tried in turn.
+    fn connect_peer(host: &str, port: &str) -> io::Result<TcpStream> {
+        let timeout =
+            Duration::from_secs(env_setting("SLAVE_TIMEOUT_SECS", DEFAULT_SLAVE_TIMEOUT_SECS).0);
+        let port: u16 = port
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses", host),
+        );
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // `ip_addrs` is one master address or several separated by commas, tried in order.
+    fn join(ip_addrs: &str, force: bool) -> Result<(), Box<dyn Error>> {
+        let master_swarm = if force {
+            None
+        } else {
+            Self::local_master_swarm()?
+        };
+        match (Self::verify_config()?, master_swarm) {
+            (Some(_), _) => {
+                info!("You are already part of a swarm. Type --help for more.")
+            }
+            (None, Some(swarm_id)) => {
+                eprintln!(
+                    "This node is the master of swarm {}. Joining another swarm would turn it \
+                     into a slave; use --force if that is what you want.",
+                    swarm_id
+                );
+            }
+            (None, None) => {
+                let master_key =
+                    std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
+
+                // SWARM_ID pins the swarm we mean to join, so a reused key cannot land
+                // us in a different one.
+                let command = match env::var("SWARM_ID") {
+                    Ok(swarm_id) => format!("JOIN {} {}", master_key, swarm_id),
+                    Err(_) => format!("JOIN {}", master_key),
+                };
+                let candidates: Vec<&str> = ip_addrs
+                    .split(',')
+                    .map(|candidate| candidate.trim())
+                    .filter(|candidate| !candidate.is_empty())
+                    .collect();
+
+                match Self::join_first(&candidates, &command) {
+                    Some((ip_addr, local_port, swarm_id)) => {
+                        if candidates.len() > 1 {
+                            info!("Joined through {}", ip_addr);
+                        }
+                        // request_join already split it, so this can't fail.
+                        let (master_ip, master_port) =
+                            split_host_port(&ip_addr).unwrap_or((&ip_addr, ""));
+                        Self::create_config(
+                            master_ip.to_string(),
+                            master_port.to_string(),
+                            local_port.to_string(),
+                            swarm_id,
+                        )?;
+                    }
+                    None => eprintln!("No master accepted the join."),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The swarm this node is master of, if its database says it has served as one.
+    fn local_master_swarm() -> DBResult<Option<String>> {
+        Self::master_swarm_at(&env_setting("DB_PATH", DB_FILE.to_string()).0)
+    }
+
+    // Looks without touching: the file is opened read-only, so join never creates it,
+    // adds tables or bumps its schema version. No file or no meta table means the node
+    // has never been a master.
+    fn master_swarm_at(path: &str) -> DBResult<Option<String>> {
+        if path == ":memory:" || !Path::new(path).exists() {
+            return Ok(None);
+        }
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let has_meta: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_meta {
+            return Ok(None);
+        }
+        Self::master_swarm(&db)
+    }
+
+    fn master_swarm(db: &Connection) -> DBResult<Option<String>> {
+        Self::meta_get(db, "swarm_id")
+    }
+
+    // Sends `command` to each candidate until one lets us join. Returns that address,
+    // the local port the master recorded for us, and the swarm id it reported.
+    fn join_first(candidates: &[&str], command: &str) -> Option<(String, u16, Option<String>)> {
+        for candidate in candidates {
+            debug!("Connecting to {}", candidate);
+            match Self::request_join(candidate, command) {
+                Ok(Some((local_port, swarm_id))) => {
+                    return Some((candidate.to_string(), local_port, swarm_id));
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Could not reach {}: {}", candidate, e),
+            }
+        }
+        None
+    }
+
+    fn request_join(ip_addr: &str, command: &str) -> io::Result<Option<(u16, Option<String>)>> {
+        let Some((host, port)) = split_host_port(ip_addr) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not host:port", ip_addr),
+            ));
+        };
+        let mut stream = Self::connect_peer(host, port)?;
+        let socket = stream.local_addr()?;
+        writeln!(stream, "{}", command)?;
+
+        let mut response = String::new();
+        let mut reader = BufReader::new(stream);
+        read_reply(&mut reader, &mut response)?;
+        let response = response.trim();
+        info!("Server: {}", response);
+        if !response.contains("joined") {
+            return Ok(None);
+        }
+        let mut swarm_line = String::new();
+        reader.read_line(&mut swarm_line)?;
+        let swarm_id = swarm_line
+            .trim()
+            .strip_prefix("SWARM ")
+            .map(|id| id.to_string());
+        Ok(Some((socket.port(), swarm_id)))
+    }
+
+    fn leave() -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
+                let master = format!("{}:{}", config.master_ip_address, config.master_port);
+                // LEAVE is idempotent, so a reply lost on the way back is safe to retry.
+                let mut backoff = Duration::from_millis(500);
+                let mut attempt = 1;
+                let response = loop {
+                    match Self::request_leave(&config) {
+                        Ok(response) => break response,
+                        Err(e) if attempt < LEAVE_ATTEMPTS => {
+                            eprintln!("Could not leave through {}: {}, retrying", master, e);
+                            thread::sleep(backoff);
+                            backoff *= 2;
+                            attempt += 1;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                };
+                info!("Server: {}", response);
+
+                if response.contains("left") || response == "200 ALREADY_LEFT" {
+                    fs::remove_file("config.txt")?;
+                    info!("Config file has been deleted!")
+                }
+            }
+            None => {
+                eprintln!("You are not a part of any swarm! no action required.");
+            }
+        }
+        Ok(())
+    }
+
+    // Names the port this node joined with, which is how the master recorded it.
+    fn request_leave(config: &Config) -> io::Result<String> {
+        let mut stream =
+            Self::connect_peer(&config.master_ip_address, &config.master_port.to_string())?;
+        writeln!(stream, "LEAVE {}", config.slave_port)?;
+        let mut response = String::new();
+        read_reply(&mut BufReader::new(stream), &mut response)?;
+        if response.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed without a reply",
+            ));
+        }
+        Ok(response.trim().to_string())
+    }
+
+    fn list(pattern: Option<&str>) -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
                 let mut stream = TcpStream::connect(format!(
                     "{}:{}",
                     config.master_ip_address, config.master_port
                 ))?;
-                writeln!(stream, "LIST")?;
+                match pattern {
+                    Some(pattern) => writeln!(stream, "LIST {}", pattern)?,
+                    None => writeln!(stream, "LIST")?,
+                }
                 let mut response = String::new();
                 let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                println!("Server: {}", response.trim());
+                read_reply(&mut reader, &mut response)?;
+                info!("Server: {}", response.trim());
 
                 let mut files: Vec<String> = Vec::new();
                 loop {
@@ -147,7 +1199,7 @@ impl Server {
                         break;
                     }
                     let line = response.trim();
-                    if line.contains("done") {
+                    if line == "DONE" {
                         break;
                     }
                     if line.is_empty() {
@@ -169,35 +1221,526 @@ impl Server {
         Ok(())
     }
 
+    fn count() -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
+                let mut stream = TcpStream::connect(format!(
+                    "{}:{}",
+                    config.master_ip_address, config.master_port
+                ))?;
+                writeln!(stream, "COUNT {}", Self::client_key(&config))?;
+                let mut counts = (None, None);
+                for line in BufReader::new(stream).lines() {
+                    let line = line?;
+                    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                        ["ACTIVE", active] => counts.0 = Some(active.to_string()),
+                        ["TOTAL", total] => counts.1 = Some(total.to_string()),
+                        ["BANNER", ..] => {}
+                        _ => info!("Server: {}", line),
+                    }
+                }
+                if let (Some(active), Some(total)) = counts {
+                    println!("🧮 Nodes: {} active, {} total", active, total);
+                }
+            }
+            None => {
+                eprintln!("You can not count nodes because you are not a part of a swarm.");
+            }
+        }
+        Ok(())
+    }
+
+    fn usage() -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
+                let mut stream = TcpStream::connect(format!(
+                    "{}:{}",
+                    config.master_ip_address, config.master_port
+                ))?;
+                writeln!(stream, "USAGE {}", Self::client_key(&config))?;
+                let reader = BufReader::new(stream);
+                for line in reader.lines() {
+                    let line = line?;
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    match parts.as_slice() {
+                        ["NODE", node, "UNREACHABLE"] => println!("💾 {}: unreachable", node),
+                        ["NODE", node, files, bytes] => {
+                            println!("💾 {}: {} files, {} bytes", node, files, bytes)
+                        }
+                        ["TOTAL", files, bytes] => {
+                            println!("Swarm total: {} files, {} bytes", files, bytes)
+                        }
+                        ["BANNER", ..] => {}
+                        _ => info!("Server: {}", line),
+                    }
+                }
+            }
+            None => {
+                eprintln!("You can not query storage usage because you are not a part of a swarm.");
+            }
+        }
+        Ok(())
+    }
+
+    fn evict(node: &str) -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
+                let mut stream = TcpStream::connect(format!(
+                    "{}:{}",
+                    config.master_ip_address, config.master_port
+                ))?;
+                writeln!(stream, "EVICT {} {}", Self::client_key(&config), node)?;
+                let mut response = String::new();
+                read_reply(&mut BufReader::new(stream), &mut response)?;
+                info!("Server: {}", response.trim());
+            }
+            None => {
+                eprintln!("You can not evict a node because you are not a part of a swarm.");
+            }
+        }
+        Ok(())
+    }
+
+    // The key this node authenticates with: the one handed over by REKEY if any,
+    // otherwise MASTER_KEY.
+    fn client_key(config: &Config) -> String {
+        match &config.master_key {
+            Some(key) => key.clone(),
+            None => env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set"),
+        }
+    }
+
+    // Asks `ip_addr`, or our master when none is given, who the current master is.
+    fn master(ip_addr: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let config = Self::verify_config()?;
+        let target = match (ip_addr, &config) {
+            (Some(ip_addr), _) => ip_addr.to_string(),
+            (None, Some(config)) => {
+                format!("{}:{}", config.master_ip_address, config.master_port)
+            }
+            (None, None) => {
+                eprintln!("Give a node to ask, you are not a part of a swarm.");
+                return Ok(());
+            }
+        };
+        let key = match &config {
+            Some(config) => Self::client_key(config),
+            None => env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set"),
+        };
+        let mut stream = TcpStream::connect(&target)?;
+        writeln!(stream, "MASTER {}", key)?;
+        let mut response = String::new();
+        read_reply(&mut BufReader::new(stream), &mut response)?;
+        match response.trim().strip_prefix("MASTER ") {
+            Some(master) => println!("👑 Master: {}", master),
+            None => info!("Server: {}", response.trim()),
+        }
+        Ok(())
+    }
+
+    // Sends each line read from stdin to `ip_addr` and prints the reply, so a script
+    // can issue many commands over one connection (see handle_connection's SESSION).
+    fn session(ip_addr: &str) -> Result<(), Box<dyn Error>> {
+        let mut stream = TcpStream::connect(ip_addr)?;
+        Self::set_keepalive(&stream)?;
+        writeln!(stream, "SESSION")?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut reply = String::new();
+        read_reply(&mut reader, &mut reply)?;
+        if reply.trim() != "200 OK" {
+            return Err(format!("{} refused the session: {}", ip_addr, reply.trim()).into());
+        }
+        for command in io::stdin().lock().lines() {
+            let command = command?;
+            let command = command.trim();
+            if command.is_empty() {
+                continue;
+            }
+            writeln!(stream, "{}", command)?;
+            loop {
+                reply.clear();
+                if reader.read_line(&mut reply)? == 0 {
+                    return Err(format!("{} closed the session", ip_addr).into());
+                }
+                let line = reply.trim_end_matches(['\r', '\n']);
+                if line == SESSION_END {
+                    break;
+                }
+                println!("{}", line.strip_prefix('.').unwrap_or(line));
+            }
+        }
+        Ok(())
+    }
+
+    // Fires `requests` sequential requests from each of `connections` threads and
+    // reports throughput and latency percentiles. Each request is its own
+    // connection, as the server closes the socket after answering.
+    fn benchmark(
+        ip_addr: &str,
+        connections: usize,
+        requests: usize,
+        command: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let started = Instant::now();
+        let workers: Vec<_> = (0..connections)
+            .map(|_| {
+                let ip_addr = ip_addr.to_string();
+                let command = command.to_string();
+                thread::spawn(move || {
+                    let mut latencies = Vec::with_capacity(requests);
+                    let mut failures = 0;
+                    for _ in 0..requests {
+                        let sent = Instant::now();
+                        let result = TcpStream::connect(&ip_addr).and_then(|mut stream| {
+                            writeln!(stream, "{}", command)?;
+                            let mut response = String::new();
+                            read_reply(&mut BufReader::new(stream), &mut response)
+                        });
+                        match result {
+                            Ok(_) => latencies.push(sent.elapsed()),
+                            Err(_) => failures += 1,
+                        }
+                    }
+                    (latencies, failures)
+                })
+            })
+            .collect();
+
+        let mut latencies: Vec<Duration> = Vec::new();
+        let mut failures = 0;
+        for worker in workers {
+            let (worker_latencies, worker_failures) =
+                worker.join().map_err(|_| "Benchmark worker panicked")?;
+            latencies.extend(worker_latencies);
+            failures += worker_failures;
+        }
+        let elapsed = started.elapsed();
+        latencies.sort();
+
+        let percentile = |p: f64| -> Duration {
+            if latencies.is_empty() {
+                return Duration::ZERO;
+            }
+            let index = ((latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+            latencies[index]
+        };
+        println!(
+            "📊 {} requests ({} failed) over {} connections in {:.2?}",
+            latencies.len() + failures,
+            failures,
+            connections,
+            elapsed
+        );
+        println!(
+            "   {:.1} requests/sec",
+            latencies.len() as f64 / elapsed.as_secs_f64()
+        );
+        println!(
+            "   p50 {:.2?}  p95 {:.2?}  p99 {:.2?}",
+            percentile(50.0),
+            percentile(95.0),
+            percentile(99.0)
+        );
+        Ok(())
+    }
+
+    // Prints the settings `serve` would run with and where each one comes from.
+    fn dump_config() {
+        let config = match Self::verify_config() {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("⚠️ {}", e);
+                return;
+            }
+        };
+        let mut settings: Vec<(&str, String, &str)> = Vec::new();
+        match &config {
+            Some(config) => {
+                settings.push(("role", "slave".to_string(), "config.txt"));
+                let (ip, source) = Self::bind_ip();
+                settings.push((
+                    "bind_address",
+                    SocketAddr::new(ip, config.slave_port as u16).to_string(),
+                    if source != "default" {
+                        source
+                    } else {
+                        "config.txt"
+                    },
+                ));
+                settings.push((
+                    "master_address",
+                    format!("{}:{}", config.master_ip_address, config.master_port),
+                    "config.txt",
+                ));
+            }
+            None => {
+                settings.push(("role", "master".to_string(), "default"));
+                let (ip, source) = Self::bind_ip();
+                settings.push((
+                    "bind_address",
+                    SocketAddr::new(ip, 8777).to_string(),
+                    source,
+                ));
+            }
+        }
+        let (db_path, source) = env_setting("DB_PATH", DB_FILE.to_string());
+        settings.push(("db_path", db_path, source));
+        settings.push(("storage_dir", STORAGE_DIR.to_string(), "default"));
+        let (value, source) = env_setting("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS);
+        settings.push(("max_connections", value.to_string(), source));
+        let (value, source) = env_setting("SLAVE_TIMEOUT_SECS", DEFAULT_SLAVE_TIMEOUT_SECS);
+        settings.push(("slave_timeout_secs", value.to_string(), source));
+        let (value, source) = env_setting("IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT_SECS);
+        settings.push(("idle_timeout_secs", value.to_string(), source));
+        let (value, source) = env_setting("REKEY_GRACE_SECS", DEFAULT_REKEY_GRACE_SECS);
+        settings.push(("rekey_grace_secs", value.to_string(), source));
+        let (value, source) = env_setting("KEEPALIVE_IDLE_SECS", DEFAULT_KEEPALIVE_IDLE_SECS);
+        settings.push(("keepalive_idle_secs", value.to_string(), source));
+        let (value, source) =
+            env_setting("KEEPALIVE_INTERVAL_SECS", DEFAULT_KEEPALIVE_INTERVAL_SECS);
+        settings.push(("keepalive_interval_secs", value.to_string(), source));
+        let (value, source) = env_setting("KEEPALIVE_RETRIES", DEFAULT_KEEPALIVE_RETRIES);
+        settings.push(("keepalive_retries", value.to_string(), source));
+        let (value, source) = env_setting("DB_BUSY_TIMEOUT_MS", DEFAULT_DB_BUSY_TIMEOUT_MS);
+        settings.push(("db_busy_timeout_ms", value.to_string(), source));
+        let (value, source) = env_setting("BANNER", false);
+        settings.push(("banner", value.to_string(), source));
+        let (value, source) = env_setting("MOTD", String::new());
+        settings.push(("motd", value, source));
+        let (value, source) = env_setting("SWARM_LOCK_DIR", env::temp_dir().display().to_string());
+        settings.push(("swarm_lock_dir", value, source));
+        let (value, source) = env_setting("ACCEPT_RATE", 0.0_f64);
+        settings.push(("accept_rate", value.to_string(), source));
+        let (burst, source) = env_setting("ACCEPT_BURST", value.ceil());
+        settings.push(("accept_burst", burst.to_string(), source));
+        let (value, source) = env_setting("HTTP_PORT", 0u16);
+        settings.push(("http_port", value.to_string(), source));
+        let (value, source) = env_setting("COMMAND_ALIASES", String::new());
+        settings.push(("command_aliases", value, source));
+        let (value, source) = env_setting("REQUEST_LOG", RequestLog::Off);
+        settings.push(("request_log", value.name().to_string(), source));
+        let key_source = match &config {
+            Some(Config {
+                master_key: Some(_),
+                ..
+            }) => "config.txt",
+            _ if env::var("MASTER_KEY").is_ok() => "env",
+            _ => "unset",
+        };
+        let key_value = if key_source == "unset" {
+            "<unset>"
+        } else {
+            "<redacted>"
+        };
+        settings.push(("master_key", key_value.to_string(), key_source));
+        let verbosity = match VERBOSITY.load(Ordering::Relaxed) {
+            QUIET => ("quiet", "flag"),
+            VERBOSE => ("verbose", "flag"),
+            _ => ("normal", "default"),
+        };
+        settings.push(("verbosity", verbosity.0.to_string(), verbosity.1));
+
+        for (name, value, source) in settings {
+            println!("{:<20} = {:<30} ({})", name, value, source);
+        }
+    }
+
+    fn commands(ip_addr: &str) -> Result<(), Box<dyn Error>> {
+        let mut stream = TcpStream::connect(ip_addr)?;
+        writeln!(stream, "COMMANDS")?;
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() && !line.starts_with("BANNER ") {
+                println!("{}", line);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs local setup checks and prints what passed, what failed and how to fix it.
+    // Returns whether everything passed. Nothing is changed except a probe file in
+    // storage/ that is removed straight away.
+    fn doctor() -> bool {
+        let mut checks: Vec<(bool, String, &str)> = Vec::new();
+        let (config, config_error) = match Self::verify_config() {
+            Ok(config) => (config, None),
+            Err(e) => (None, Some(e)),
+        };
+
+        let key = match &config {
+            Some(Config {
+                master_key: Some(key),
+                ..
+            }) => Some(key.clone()),
+            _ => env::var("MASTER_KEY").ok(),
+        };
+        checks.push(match key {
+            None => (
+                false,
+                "master key is not set".to_string(),
+                "set MASTER_KEY in .env or the environment",
+            ),
+            Some(key) if key.len() < MIN_KEY_LEN => (
+                false,
+                format!("master key is only {} characters", key.len()),
+                "use a random key of at least 16 characters",
+            ),
+            Some(_) => (true, "master key is set".to_string(), ""),
+        });
+
+        if let Some(e) = config_error {
+            checks.push((false, e, "fix config.txt, or run leave and join again"));
+        }
+        if let Some(config) = &config {
+            checks.push(match config.validate() {
+                Ok(()) => (true, "config.txt is valid".to_string(), ""),
+                Err(e) => (false, e, "fix config.txt, or run leave and join again"),
+            });
+        }
+
+        let db_path = env_setting("DB_PATH", DB_FILE.to_string()).0;
+        checks.push(if db_path == ":memory:" {
+            (true, "database is in memory".to_string(), "")
+        } else if Path::new(&db_path).exists() {
+            match Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+                .and_then(|db| db.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)))
+            {
+                Ok(version) if version <= SCHEMA_VERSION => {
+                    (true, format!("database {} is writable", db_path), "")
+                }
+                Ok(version) => (
+                    false,
+                    format!("database {} has newer schema version {}", db_path, version),
+                    "upgrade this binary or point DB_PATH at another file",
+                ),
+                Err(e) => (
+                    false,
+                    format!("database {} cannot be opened for writing: {}", db_path, e),
+                    "check the file's permissions or set DB_PATH",
+                ),
+            }
+        } else {
+            (true, format!("database {} will be created", db_path), "")
+        });
+
+        checks.push(if Self::storage_writable() {
+            (
+                true,
+                format!("storage directory '{}' is writable", STORAGE_DIR),
+                "",
+            )
+        } else {
+            (
+                false,
+                format!(
+                    "storage directory '{}' is missing or not writable",
+                    STORAGE_DIR
+                ),
+                "create it with mkdir storage and check its permissions",
+            )
+        });
+
+        let bind = match &config {
+            Some(config) => SocketAddr::new(Self::bind_ip().0, config.slave_port as u16),
+            None => SocketAddr::new(Self::bind_ip().0, 8777),
+        };
+        checks.push(match TcpListener::bind(bind) {
+            Ok(_) => (true, format!("{} is free", bind), ""),
+            Err(e) => (
+                false,
+                format!("cannot bind {}: {}", bind, e),
+                "stop whatever holds the port (serve will fall back to a random one)",
+            ),
+        });
+
+        if let Some(config) = &config {
+            let master = format!("{}:{}", config.master_ip_address, config.master_port);
+            let healthy =
+                Self::connect_peer(&config.master_ip_address, &config.master_port.to_string())
+                    .and_then(|mut stream| {
+                        writeln!(stream, "HEALTH")?;
+                        let mut response = String::new();
+                        read_reply(&mut BufReader::new(stream), &mut response)?;
+                        Ok(response)
+                    });
+            checks.push(match healthy {
+                Ok(response) if response.starts_with("200") => {
+                    (true, format!("master {} is healthy", master), "")
+                }
+                Ok(response) => (
+                    false,
+                    format!("master {} answered {}", master, response.trim()),
+                    "check the master's logs",
+                ),
+                Err(e) => (
+                    false,
+                    format!("master {} is unreachable: {}", master, e),
+                    "start the master, or fix master_ip_address/master_port in config.txt",
+                ),
+            });
+        }
+
+        let mut healthy = true;
+        for (ok, detail, hint) in &checks {
+            if *ok {
+                println!("✅ {}", detail);
+            } else {
+                healthy = false;
+                println!("❌ {}", detail);
+                println!("   hint: {}", hint);
+            }
+        }
+        healthy
+    }
+
     fn create_config(
         ip: String,
         master_port: String,
         slave_port: String,
+        swarm_id: Option<String>,
     ) -> Result<(), Box<dyn Error>> {
         let filename = "config.txt";
         if !Path::new(filename).exists() {
-            let data = format!(
+            let mut data = format!(
                 "master_ip_address={}\nmaster_port={}\nslave_port={}",
                 ip, master_port, slave_port
             );
+            if let Some(swarm_id) = swarm_id {
+                data.push_str(&format!("\nswarm_id={}", swarm_id));
+            }
             fs::write(filename, data)?;
-            println!("Config file has been created!");
+            info!("Config file has been created!");
         } else {
-            println!("Config file already exist!");
+            info!("Config file already exist!");
         }
         Ok(())
     }
 
-    fn verify_config() -> Option<Config> {
+    // Reads config.txt, or None when this node has not joined a swarm. Fails when the
+    // file cannot be read or a `${VAR}` in it cannot be expanded.
+    fn verify_config() -> Result<Option<Config>, String> {
         let filename = "config.txt";
         if Path::new(filename).exists() {
-            let content =
-                fs::read_to_string(filename).expect("Config file exist but no read permissions.");
+            let content = fs::read_to_string(filename)
+                .map_err(|e| format!("could not read {}: {}", filename, e))?;
             let mut master_ip_address = String::new();
             let mut master_port: u32 = 8777;
             let mut slave_port: u32 = 8777;
+            let mut master_key = None;
+            let mut swarm_id = None;
             for line in content.lines() {
                 if let Some((key, value)) = line.split_once('=') {
+                    // The key is written back verbatim by join and REKEY, so a `$` in it
+                    // is never a variable.
+                    let expanded;
+                    let value = if key == "master_key" {
+                        value
+                    } else {
+                        expanded = interpolate(value, |name| env::var(name).ok())
+                            .map_err(|e| format!("{} {}: {}", filename, key, e))?;
+                        &expanded
+                    };
                     match key {
                         "master_ip_address" => master_ip_address = value.to_string(),
                         "master_port" => {
@@ -210,21 +1753,149 @@ impl Server {
                                 slave_port = port;
                             }
                         }
+                        "master_key" => master_key = Some(value.to_string()),
+                        "swarm_id" => swarm_id = Some(value.to_string()),
                         _ => {}
                     }
                 }
             }
-            return Some(Config {
+            return Ok(Some(Config {
                 master_ip_address,
                 master_port,
                 slave_port,
-            });
+                master_key,
+                swarm_id,
+            }));
+        }
+        Ok(None)
+    }
+
+    // Prints this node's role and the swarm it belongs to.
+    fn whoami() -> Result<(), Box<dyn Error>> {
+        match Self::verify_config()? {
+            Some(config) => {
+                info!("Role: slave");
+                info!(
+                    "Master: {}:{}",
+                    config.master_ip_address, config.master_port
+                );
+                info!(
+                    "Swarm: {}",
+                    config.swarm_id.as_deref().unwrap_or("<unknown>")
+                );
+            }
+            None => {
+                let db = Self::build_db()?;
+                let swarm_id = Self::meta_get(&db, "swarm_id")?;
+                info!("Role: master");
+                info!(
+                    "Swarm: {}",
+                    swarm_id.as_deref().unwrap_or("<not started yet>")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn update_config_key(master_key: &str) -> Result<(), Box<dyn Error>> {
+        let filename = "config.txt";
+        let content = fs::read_to_string(filename)?;
+        let mut lines: Vec<String> = content
+            .lines()
+            .filter(|line| !line.starts_with("master_key="))
+            .map(|line| line.to_string())
+            .collect();
+        lines.push(format!("master_key={}", master_key));
+        fs::write(filename, lines.join("\n"))?;
+        Ok(())
+    }
+
+    fn snapshot(path: &str) -> Result<(), Box<dyn Error>> {
+        if Path::new(path).exists() {
+            return Err(format!("Snapshot file {} already exists.", path).into());
+        }
+        let db = Self::build_db()?;
+        db.execute("VACUUM INTO ?1", [path])?;
+        info!("📦 Snapshot has been written to {}", path);
+        Ok(())
+    }
+
+    fn restore(path: &str, force: bool) -> Result<(), Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Err(format!("Snapshot file {} does not exist.", path).into());
+        }
+        let snapshot = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let version: i64 = snapshot.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            return Err(format!(
+                "Snapshot schema version {} does not match the expected version {}.",
+                version, SCHEMA_VERSION
+            )
+            .into());
+        }
+        drop(snapshot);
+
+        let restored = Self::restore_into(&mut Self::build_db()?, path, force)?;
+        info!("♻️ Restored {} servers from {}", restored, path);
+        Ok(())
+    }
+
+    // Replaces every table of `db` with the snapshot at `path` and returns how many
+    // servers it held. Refuses without `force` if `db` holds anything at all: key
+    // hashes, swarm id and audit log are as much worth keeping as the servers.
+    fn restore_into(db: &mut Connection, path: &str, force: bool) -> Result<usize, Box<dyn Error>> {
+        let (servers, meta, events): (i64, i64, i64) = db.query_row(
+            "SELECT (SELECT COUNT(*) FROM servers), (SELECT COUNT(*) FROM meta),
+                (SELECT COUNT(*) FROM events)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        if servers + meta + events > 0 && !force {
+            return Err(format!(
+                "Database already holds {} servers, {} settings and {} events. \
+                 Use --force to overwrite it.",
+                servers, meta, events
+            )
+            .into());
         }
-        return None;
+
+        db.execute("ATTACH DATABASE ?1 AS snapshot", [path])?;
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM servers", [])?;
+        tx.execute("DELETE FROM meta", [])?;
+        tx.execute("DELETE FROM events", [])?;
+        let restored = tx.execute(
+            "INSERT INTO servers (id, ip_address, port, is_active, has_left)
+                SELECT id, ip_address, port, is_active, has_left FROM snapshot.servers",
+            [],
+        )?;
+        tx.execute(
+            "INSERT INTO meta (key, value) SELECT key, value FROM snapshot.meta",
+            [],
+        )?;
+        tx.execute(
+            "INSERT INTO events (id, at, kind, actor, details)
+                SELECT id, at, kind, actor, details FROM snapshot.events",
+            [],
+        )?;
+        tx.commit()?;
+        db.execute("DETACH DATABASE snapshot", [])?;
+        Ok(restored)
     }
 
+    // DB_PATH=:memory: keeps the database in memory; it lives as long as the process.
     fn build_db() -> DBResult<Connection> {
-        let conn = Connection::open("master_node.db")?;
+        let path = env_setting("DB_PATH", DB_FILE.to_string()).0;
+        Self::open_db(&path)
+    }
+
+    fn open_db(path: &str) -> DBResult<Connection> {
+        let conn = Connection::open(path)?;
+        // Another process (snapshot, restore, a second serve) may hold the file's lock;
+        // wait for it rather than failing the command outright.
+        conn.busy_timeout(Duration::from_millis(
+            env_setting("DB_BUSY_TIMEOUT_MS", DEFAULT_DB_BUSY_TIMEOUT_MS).0,
+        ))?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS servers (
                     id INTEGER PRIMARY KEY,
@@ -235,226 +1906,2847 @@ impl Server {
                 )",
             [],
         )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS meta (
+                    key VARCHAR PRIMARY KEY,
+                    value VARCHAR NOT NULL
+                )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS events (
+                    id INTEGER PRIMARY KEY,
+                    at INTEGER NOT NULL,
+                    kind VARCHAR NOT NULL,
+                    actor VARCHAR NOT NULL,
+                    details VARCHAR NOT NULL DEFAULT ''
+                )",
+            [],
+        )?;
+        Self::canonicalize_addresses(&conn)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
         Ok(conn)
     }
 
-    fn handle_connection(
-        mut stream: TcpStream,
-        master_key: String,
-        address: SocketAddr,
-        db: Arc<Mutex<Connection>>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut reader = BufReader::new(stream.try_clone()?);
-        let mut command = String::new();
-        reader.read_line(&mut command)?;
-        let command = command.trim();
-        println!("{}", command);
-
-        let commands: Vec<&str> = command.split(" ").collect();
-
-        match commands[0] {
-            "JOIN" => {
-                if master_key == commands[1].to_string() {
-                    let db = db.lock().unwrap();
-                    let mut stmt = db.prepare(
-                        "SELECT COUNT(*) FROM servers WHERE ip_address = ?1 AND port = ?2",
-                    )?;
-                    let exists: i64 = stmt.query_row(
-                        [address.ip().to_string(), address.port().to_string()],
-                        |row| row.get(0),
-                    )?;
-                    if exists > 0 {
-                        writeln!(stream, "Server already exists!")?;
-                    } else {
-                        db.execute(
-                            "INSERT INTO servers (ip_address, port) VALUES (?1, ?2)",
-                            [address.ip().to_string(), address.port().to_string()],
-                        )?;
-                        writeln!(stream, "Swam has been joined!")?;
-                    }
-                } else {
-                    writeln!(stream, "Authentication failed!")?;
-                }
+    // Rewrites rows stored before addresses were canonicalized.
+    fn canonicalize_addresses(db: &Connection) -> DBResult<()> {
+        for server in servers_repo::list_all(db)? {
+            let Ok(ip) = server.ip_address.parse::<IpAddr>() else {
+                continue;
+            };
+            let canonical = canonical_ip(ip).to_string();
+            if canonical != server.ip_address {
+                servers_repo::set_ip_address(db, server.id, &canonical)?;
             }
-            "LEAVE" => {
-                let db = db.lock().unwrap();
-                let mut stmt =
-                    db.prepare("SELECT has_left FROM servers WHERE ip_address = ?1 AND port= ?2")?;
-                let result = stmt.query_row(
-                    [address.ip().to_string(), address.port().to_string()],
-                    |row| row.get::<_, bool>(0),
+        }
+        Ok(())
+    }
+
+    fn meta_get(db: &Connection, key: &str) -> DBResult<Option<String>> {
+        db.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()
+    }
+
+    fn meta_set(db: &Connection, key: &str, value: &str) -> DBResult<()> {
+        db.execute(
+            "INSERT INTO meta (key, value) VALUES (?1, ?2)
+                ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    // Appends to the audit trail queried by EVENTS. A failed write is logged rather
+    // than failing the membership change it describes.
+    fn record_event(db: &Connection, kind: &str, actor: SocketAddr, details: &str) {
+        if let Err(e) = db.execute(
+            "INSERT INTO events (at, kind, actor, details) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![Self::now_secs() as i64, kind, actor.to_string(), details],
+        ) {
+            eprintln!("Could not record {} event: {}", kind, e);
+        }
+    }
+
+    fn hash_key(key: &str) -> String {
+        Self::content_hash(key.as_bytes())
+    }
+
+    fn content_hash(bytes: &[u8]) -> String {
+        Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+
+    // Records the key on first boot. Afterwards a different MASTER_KEY would lock out
+    // every node holding the stored one, so startup is refused unless `accept_change`
+    // says to adopt it. A key that was rekeyed away from is left in place with a warning.
+    fn seed_key(
+        db: &Connection,
+        master_key: &str,
+        accept_change: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let hash = Self::hash_key(master_key);
+        match Self::meta_get(db, "current_key_hash")? {
+            None => Self::meta_set(db, "current_key_hash", &hash)?,
+            Some(current) if current == hash => {}
+            Some(_) if accept_change => {
+                eprintln!(
+                    "⚠️ MASTER_KEY has changed; adopting it. Nodes using the old key are locked out."
+                );
+                Self::meta_set(db, "current_key_hash", &hash)?;
+                db.execute(
+                    "DELETE FROM meta WHERE key IN ('previous_key_hash', 'rekey_grace_until')",
+                    [],
+                )?;
+            }
+            Some(_)
+                if Self::meta_get(db, "previous_key_hash")?.as_deref() == Some(hash.as_str()) =>
+            {
+                eprintln!(
+                    "⚠️ MASTER_KEY is the key replaced by REKEY; the current key stays in use."
+                );
+            }
+            Some(_) => {
+                return Err(
+                    "MASTER_KEY differs from the key this node was set up with. Use REKEY to \
+                            change keys, or restart with --accept-key-change to adopt the new one."
+                        .into(),
                 );
-                match result {
-                    Ok(has_left) => {
-                        if has_left {
-                            writeln!(stream, "You have already left!")?;
-                        } else {
-                            db.execute(
-                                "UPDATE servers SET has_left = true WHERE ip_address = ?1 AND port= ?2",
-                                [address.ip().to_string(), address.port().to_string()],
-                            )?;
-                            writeln!(stream, "Swam has been left!")?;
-                        }
-                    }
-                    Err(_) => {
-                        db.execute(
-                            "INSERT INTO servers (ip_address, has_left) VALUES (?1, ?2)",
-                            rusqlite::params![address.ip().to_string(), false],
-                        )?;
-                        writeln!(stream, "Swam has been joined!")?;
-                    }
-                }
             }
+        }
+        Ok(())
+    }
 
-            "LIST" => {
-                writeln!(stream, "Collecting data from servers...")?;
-                let db = db.lock().unwrap();
-                let mut stmt =
-                    db.prepare("SELECT ip_address, port FROM servers WHERE has_left = 0")?;
-                let servers = stmt.query_map([], |row| {
-                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-                })?;
-                let mut active_servers: Vec<(String, String)> = Vec::new();
-                for server in servers {
-                    match server {
-                        Ok((ip, port)) => {
-                            active_servers.push((ip, port));
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading server: {}", e);
-                        }
+    // Names the swarm on first boot so nodes can tell independent swarms apart.
+    fn seed_swarm_id(db: &Connection) -> DBResult<String> {
+        match Self::meta_get(db, "swarm_id")? {
+            Some(swarm_id) => Ok(swarm_id),
+            None => {
+                let swarm_id = Uuid::new_v4().to_string();
+                Self::meta_set(db, "swarm_id", &swarm_id)?;
+                Ok(swarm_id)
+            }
+        }
+    }
+
+    // Accepts the current key, or the previous one while a rekey grace window is open.
+    fn authenticate(db: &Connection, key: &str) -> DBResult<bool> {
+        let hash = Self::hash_key(key);
+        if Self::meta_get(db, "current_key_hash")?.as_deref() == Some(hash.as_str()) {
+            return Ok(true);
+        }
+        if Self::meta_get(db, "previous_key_hash")?.as_deref() == Some(hash.as_str()) {
+            let grace_until = Self::meta_get(db, "rekey_grace_until")?
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(0);
+            return Ok(Self::now_secs() < grace_until);
+        }
+        Ok(false)
+    }
+
+    // Resolves the key's role and checks it against `required`. Returns the response
+    // to send back when the key is unknown or not privileged enough.
+    fn authorize(
+        db: &Connection,
+        key: &str,
+        required: Role,
+    ) -> Result<Option<Response>, Box<dyn Error>> {
+        let role = if Self::authenticate(db, key)? {
+            Some(Role::Admin)
+        } else {
+            match env::var("KEYS_FILE") {
+                Ok(path) => match fs::read_to_string(&path) {
+                    Ok(keys) => Role::lookup(&keys, &Self::hash_key(key)),
+                    Err(e) => {
+                        eprintln!("Could not read KEYS_FILE {}: {}", path, e);
+                        None
                     }
+                },
+                Err(_) => None,
+            }
+        };
+        Ok(match role {
+            None => Some(Response::line("Authentication failed!")),
+            Some(role) if role < required => Some(Response::line("403 FORBIDDEN")),
+            Some(_) => None,
+        })
+    }
+
+    fn rekey_grace_secs() -> u64 {
+        env_setting("REKEY_GRACE_SECS", DEFAULT_REKEY_GRACE_SECS).0
+    }
+
+    // A connection answers one command and closes, unless its first line is SESSION:
+    // then the server replies 200 OK and answers commands until the client hangs up,
+    // ending each reply with a SESSION_END line. A SUBSCRIBE stream runs to the end of
+    // the connection, so it ends the session too; finite streams such as LIST don't.
+    // Waiting longer than IDLE_TIMEOUT_SECS for a command line fails the connection.
+    fn handle_connection(mut stream: TcpStream, ctx: &Ctx) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Self::idle_timeout())?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut command = String::new();
+        reader.read_line(&mut command)?;
+        if command.trim() != "SESSION" {
+            Self::answer(command.trim(), &mut stream, ctx, false)?;
+            return Ok(());
+        }
+        writeln!(stream, "200 OK")?;
+        loop {
+            command.clear();
+            if reader.read_line(&mut command)? == 0 {
+                return Ok(());
+            }
+            if command.trim().is_empty() {
+                continue;
+            }
+            if Self::answer(command.trim(), &mut stream, ctx, true)? {
+                return Ok(());
+            }
+            writeln!(stream, "{}", SESSION_END)?;
+        }
+    }
+
+    // Runs one command and writes its reply, dot-stuffed on a session. Returns true for
+    // a subscription, which has used up the connection.
+    fn answer(
+        command: &str,
+        stream: &mut TcpStream,
+        ctx: &Ctx,
+        session: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        debug!("{} -> {}", ctx.address, command);
+
+        let started = Instant::now();
+        let result = Self::process_with_retry(command, ctx);
+        let code = match &result {
+            Ok(response) => reply_code(&response.lines),
+            Err(_) => Some(500),
+        };
+        let method = command.split_whitespace().next().unwrap_or("-");
+        if let Some(line) = env_setting("REQUEST_LOG", RequestLog::Off).0.line(
+            method,
+            ctx.address,
+            code,
+            started.elapsed(),
+        ) {
+            println!("{}", line);
+        }
+        let response = result?;
+        let _subscriber = response
+            .subscription
+            .then(|| SubscriberGuard::new(ctx.connections));
+        let mut write_line = |line: &str| {
+            let stuffing = if session && line.starts_with('.') {
+                "."
+            } else {
+                ""
+            };
+            writeln!(stream, "{}{}", stuffing, line)
+        };
+        for line in &response.lines {
+            write_line(line)?;
+        }
+        // Forward until the producer hangs up, or the subscriber is dropped as too slow.
+        if let Some(lines) = response.stream {
+            for line in lines {
+                write_line(&line)?;
+            }
+        }
+        Ok(response.subscription)
+    }
+
+    // Relays one slave's FILES answer to `sender`. Returns false once the client
+    // has gone away and there is no point asking the remaining slaves.
+    fn forward_files(ip: &str, port: &str, query: &str, sender: &SyncSender<String>) -> bool {
+        let mut streaming = match Self::connect_peer(ip, port) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Skipping unreachable server {}:{}: {}", ip, port, e);
+                return true;
+            }
+        };
+        if let Err(e) = writeln!(streaming, "{}", query) {
+            eprintln!("Could not query {}:{}: {}", ip, port, e);
+            return true;
+        }
+        let mut reader = BufReader::new(streaming);
+        let mut response = String::new();
+        loop {
+            response.clear();
+            match reader.read_line(&mut response) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Gave up on {}:{}: {}", ip, port, e);
+                    return true;
                 }
-                for (ip, port) in &active_servers {
-                    let mut streaming = match TcpStream::connect(format!("{}:{}", ip, port)) {
-                        Ok(stream) => stream,
-                        Err(_) => continue,
-                    };
-                    writeln!(streaming, "FILES")?;
-                    let mut reader = BufReader::new(streaming.try_clone()?);
-                    let mut response = String::new();
-                    loop {
-                        response.clear();
-                        reader.read_line(&mut response)?;
-                        let line = response.trim();
-                        if line.contains("DONE") {
-                            break;
-                        }
-                        if line.is_empty() {
-                            continue;
-                        }
-                        writeln!(stream, "{}", line)?;
-                    }
+            }
+            let line = response.trim();
+            if line == "DONE" {
+                return true;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if sender.send(line.to_string()).is_err() {
+                return false;
+            }
+        }
+    }
+
+    // The known verb closest to `verb`, if it is within a couple of typos.
+    fn suggest_command(verb: &str, registry: &Registry) -> Option<&'static str> {
+        let verb = verb.to_uppercase();
+        registry
+            .verbs()
+            .map(|known| (edit_distance(&verb, known), known))
+            .filter(|(distance, _)| *distance <= 2)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, known)| known)
+    }
+
+    // Runs `line`, retrying with backoff while the database stays locked past its busy
+    // timeout. The retry runs the whole command again, which is only safe because
+    // commands write after their checks pass and keep dependent writes (REKEY's key
+    // hashes) in one transaction, so a busy failure leaves no partial update behind.
+    fn process_with_retry(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let mut backoff = Duration::from_millis(50);
+        for _ in 0..DB_BUSY_RETRIES {
+            match ctx.registry.dispatch(line, ctx) {
+                Err(e) if Self::is_db_busy(e.as_ref()) => {
+                    debug!("Database busy, retrying {:?} in {:?}", line, backoff);
+                    thread::sleep(backoff);
+                    backoff *= 2;
                 }
+                result => return result,
             }
+        }
+        eprintln!("Database still busy after {} attempts", DB_BUSY_RETRIES);
+        Ok(Response::line("503 DB_BUSY"))
+    }
 
-            "FILES" => {
-                let entries = match fs::read_dir("storage") {
-                    Ok(entries) => entries,
-                    Err(e) => {
-                        println!("Error reading directory: {}", e);
-                        return Ok(());
-                    }
-                };
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let file_name = entry.file_name().to_string_lossy().to_string();
-                        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
-                        if !is_dir {
-                            writeln!(stream, "{}", file_name)?;
-                        }
-                    }
+    fn is_db_busy(error: &(dyn Error + 'static)) -> bool {
+        matches!(
+            error.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(failure, _))
+                if matches!(
+                    failure.code,
+                    rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                )
+        )
+    }
+
+    fn join_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let (key, requested_swarm) = match arguments(line)[..] {
+            [key] => (key, None),
+            [key, swarm_id] => (key, Some(swarm_id)),
+            _ => return Ok(invalid("JOIN")),
+        };
+        let address = canonical_address(ctx.address);
+        let db = ctx.db.lock().unwrap();
+        if let Some(denied) = Self::authorize(&db, key, Role::Admin)? {
+            return Ok(denied);
+        }
+        // Only the master names the swarm; a slave seeding its own id here would
+        // split it from the swarm it later follows.
+        if !ctx.is_master {
+            return Ok(Response::line("409 NOT_MASTER"));
+        }
+        let swarm_id = Self::seed_swarm_id(&db)?;
+        if let Some(requested_swarm) = requested_swarm
+            && requested_swarm != swarm_id
+        {
+            return Ok(Response::line("409 WRONG_SWARM"));
+        }
+        if servers_repo::find_by_address(&db, address)?.is_some() {
+            return Ok(Response::line("Server already exists!"));
+        }
+        servers_repo::insert(&db, address)?;
+        Self::record_event(&db, "JOIN", address, "");
+        ctx.events.publish(format!("EVENT JOIN {}", address));
+        Ok(Response::new(vec![
+            "Swam has been joined!".to_string(),
+            format!("SWARM {}", swarm_id),
+        ]))
+    }
+
+    // Names the port the node joined with: its LEAVE comes from a fresh connection
+    // whose source port is not the one recorded. Without it only the IP is used.
+    fn leave_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let port = match arguments(line)[..] {
+            [] => None,
+            [port] => match port.parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(_) => return Ok(invalid("LEAVE")),
+            },
+            _ => return Ok(invalid("LEAVE")),
+        };
+        let address = canonical_address(ctx.address);
+        let address = match port {
+            Some(port) => SocketAddr::new(address.ip(), port),
+            None => address,
+        };
+        let db = ctx.db.lock().unwrap();
+        // Leaving must not register anyone, or any client could become a member
+        // without a key.
+        let Some(server) = servers_repo::find_by_address(&db, address)? else {
+            return Ok(Response::line("404 NOT_A_MEMBER"));
+        };
+        if server.has_left {
+            return Ok(Response::line("200 ALREADY_LEFT"));
+        }
+        servers_repo::mark_left(&db, server.id)?;
+        Self::record_event(&db, "LEAVE", address, "");
+        ctx.events.publish(format!("EVENT LEAVE {}", address));
+        Ok(Response::line("Swam has been left!"))
+    }
+
+    fn list_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let pattern = match arguments(line)[..] {
+            [pattern] => Some(pattern),
+            _ => None,
+        };
+        let query = match pattern {
+            Some(pattern) => format!("FILES {}", pattern),
+            None => "FILES".to_string(),
+        };
+        let active_servers = {
+            let db = ctx.db.lock().unwrap();
+            servers_repo::list_active(&db)?
+                .into_iter()
+                .map(|server| (server.ip_address, server.port))
+                .collect::<Vec<_>>()
+        };
+        // File names go to the client as each slave sends them, through a
+        // bounded channel, so the master never holds a whole listing.
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        thread::spawn(move || {
+            for (ip, port) in &active_servers {
+                if !Self::forward_files(ip, port, &query, &sender) {
+                    return;
                 }
-                writeln!(stream, "DONE")?;
             }
-            _ => {
-                writeln!(stream, "Unknown command!")?;
+            let _ = sender.send("DONE".to_string());
+        });
+        Ok(Response {
+            lines: vec!["Collecting data from servers...".to_string()],
+            stream: Some(receiver),
+            subscription: false,
+        })
+    }
+
+    fn files_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let pattern = match arguments(line)[..] {
+            [pattern] => Some(pattern),
+            _ => None,
+        };
+        let mut lines: Vec<String> = Vec::new();
+        match ctx.storage.list() {
+            Ok(files) => lines.extend(
+                files
+                    .into_iter()
+                    .filter(|name| pattern.is_none_or(|pattern| glob_match(pattern, name))),
+            ),
+            Err(e) => {
+                eprintln!("Error reading directory: {}", e);
+                return Ok(Response::new(lines));
             }
         }
-        Ok(())
+        lines.push("DONE".to_string());
+        Ok(Response::new(lines))
     }
 
-    fn run(&self) -> Result<(), Box<dyn Error>> {
-        loop {
-            match self.listener.accept() {
-                Ok((stream, address)) => {
-                    let master_key = self.master_key.clone();
-                    let database = self.database.clone();
-                    thread::spawn(move || {
-                        if let Err(e) =
-                            Self::handle_connection(stream, master_key, address, database)
-                        {
-                            eprintln!("Error handling connection: {}", e);
-                        };
-                    });
+    // Rotates the swarm key; the old key stays valid for REKEY_GRACE_SECS.
+    fn rekey_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [old_key, new_key] = arguments(line)[..] else {
+            return Ok(invalid("REKEY"));
+        };
+        let address = canonical_address(ctx.address);
+        let active_servers = {
+            let db = ctx.db.lock().unwrap();
+            let current = Self::meta_get(&db, "current_key_hash")?;
+            if current.as_deref() != Some(Self::hash_key(old_key).as_str()) {
+                return Ok(Response::line("Authentication failed!"));
+            }
+            let grace_until = Self::now_secs() + Self::rekey_grace_secs();
+            // All or nothing: a new current key without its grace window would
+            // lock out the old key, and a retry could no longer authenticate.
+            let tx = db.unchecked_transaction()?;
+            Self::meta_set(&tx, "previous_key_hash", &Self::hash_key(old_key))?;
+            Self::meta_set(&tx, "current_key_hash", &Self::hash_key(new_key))?;
+            Self::meta_set(&tx, "rekey_grace_until", &grace_until.to_string())?;
+            tx.commit()?;
+
+            servers_repo::list_active(&db)?
+                .into_iter()
+                .map(|server| (server.ip_address, server.port))
+                .collect::<Vec<_>>()
+        };
+
+        if !ctx.is_master {
+            Self::update_config_key(new_key)?;
+            return Ok(Response::line("200 REKEYED"));
+        }
+
+        let mut notified = 0;
+        for (ip, port) in &active_servers {
+            let mut streaming = match Self::connect_peer(ip, port) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not notify {}:{} of the new key: {}", ip, port, e);
+                    continue;
+                }
+            };
+            let mut response = String::new();
+            let sent = writeln!(streaming, "REKEY {} {}", old_key, new_key)
+                .and_then(|_| BufReader::new(streaming).read_line(&mut response));
+            match sent {
+                Ok(_) if response.starts_with("200") => notified += 1,
+                Ok(_) => {
+                    eprintln!("{}:{} refused the new key: {}", ip, port, response.trim())
                 }
                 Err(e) => {
-                    eprintln!("Connection error: {}", e);
+                    eprintln!("Could not notify {}:{} of the new key: {}", ip, port, e)
                 }
             }
         }
+        info!("🔑 Swarm key rotated, {} slaves updated", notified);
+        Self::record_event(
+            &ctx.db.lock().unwrap(),
+            "REKEY",
+            address,
+            &format!("{}/{} slaves updated", notified, active_servers.len()),
+        );
+        ctx.events.publish("EVENT REKEY".to_string());
+        Ok(Response::line(&format!(
+            "200 REKEYED {}/{}",
+            notified,
+            active_servers.len()
+        )))
     }
-}
 
-// Main Functions
-fn main() -> Result<(), Box<dyn Error>> {
-    dotenv::dotenv().ok();
-    let args: Vec<String> = env::args().collect();
-    match args.get(1).map(|s| s.as_str()) {
-        Some("join") => {
-            if args.len() != 3 {
-                println!("Not enough arguments!");
-                print_usage();
-            } else {
-                join_handler(&args[2])?;
+    // Audit trail, newest `limit` events at or after `since`, oldest first.
+    fn events_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let args = arguments(line);
+        let Some((&key, flags)) = args.split_first() else {
+            return Ok(invalid("EVENTS"));
+        };
+        let Some((since, limit)) = event_filters(flags) else {
+            return Ok(invalid("EVENTS"));
+        };
+        let mut lines: Vec<String> = Vec::new();
+        let db = ctx.db.lock().unwrap();
+        if let Some(denied) = Self::authorize(&db, key, Role::Admin)? {
+            return Ok(denied);
+        }
+        let mut stmt = db.prepare(
+            "SELECT id, at, kind, actor, details FROM events
+                WHERE at >= ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = stmt
+            .query_map(rusqlite::params![since as i64, limit as i64], |row| {
+                Ok(format!(
+                    "{} {} {} {} {}",
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?
+                ))
+            })?
+            .collect::<DBResult<Vec<String>>>()?;
+        lines.extend(rows.into_iter().rev().map(|row| row.trim_end().to_string()));
+        lines.push("DONE".to_string());
+        Ok(Response::new(lines))
+    }
+
+    // Checkpoints the WAL, if there is one, so a backup of the database file
+    // alone sees every committed write. open_db leaves SQLite in its default
+    // rollback-journal mode, where commits already land in the file, so the reply
+    // names the journal mode and says when there was nothing to checkpoint. Writes
+    // are synchronous otherwise, so there is nothing else to drain.
+    fn flush_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key] = arguments(line)[..] else {
+            return Ok(invalid("FLUSH"));
+        };
+        let db = ctx.db.lock().unwrap();
+        if let Some(denied) = Self::authorize(&db, key, Role::Admin)? {
+            return Ok(denied);
+        }
+        let journal_mode: String = db.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Ok(Response::line(&format!(
+                "200 FLUSHED journal_mode={} checkpoint=not_needed",
+                journal_mode.to_lowercase()
+            )));
+        }
+        // FULL rather than TRUNCATE, which empties the log and then reports 0 frames.
+        let (busy, wal_frames, checkpointed): (i64, i64, i64) =
+            db.query_row("PRAGMA wal_checkpoint(FULL)", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+        if busy != 0 {
+            return Ok(Response::line("503 SERVICE_UNAVAILABLE checkpoint busy"));
+        }
+        Ok(Response::line(&format!(
+            "200 FLUSHED journal_mode=wal wal_frames={} checkpointed={}",
+            wal_frames, checkpointed
+        )))
+    }
+
+    // Who this node takes to be the master: itself, or the one in config.txt.
+    fn master_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key] = arguments(line)[..] else {
+            return Ok(invalid("MASTER"));
+        };
+        if let Some(denied) = Self::authorize(&ctx.db.lock().unwrap(), key, Role::ReadOnly)? {
+            return Ok(denied);
+        }
+        let master = if ctx.is_master {
+            Some(ctx.local_address.to_string())
+        } else {
+            Self::verify_config()
+                .unwrap_or_else(|e| {
+                    eprintln!("⚠️ {}", e);
+                    None
+                })
+                .filter(|config| config.validate().is_ok())
+                .map(|config| format!("{}:{}", config.master_ip_address, config.master_port))
+        };
+        Ok(Response::line(&format!(
+            "MASTER {}",
+            master.as_deref().unwrap_or("UNKNOWN")
+        )))
+    }
+
+    // Node counts without the full list, for cheap monitoring.
+    fn count_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key] = arguments(line)[..] else {
+            return Ok(invalid("COUNT"));
+        };
+        let db = ctx.db.lock().unwrap();
+        if let Some(denied) = Self::authorize(&db, key, Role::ReadOnly)? {
+            return Ok(denied);
+        }
+        let (active, total) = servers_repo::counts(&db)?;
+        Ok(Response::new(vec![
+            format!("ACTIVE {}", active),
+            format!("TOTAL {}", total),
+        ]))
+    }
+
+    // Removes every file matching a glob on this node and, from the master, on
+    // every active slave. Needs --dry-run or --confirm so a typo cannot wipe
+    // storage by accident.
+    fn delete_many_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let (key, pattern, dry_run) = match arguments(line)[..] {
+            [key, pattern, "--dry-run"] => (key, pattern, true),
+            [key, pattern, "--confirm"] => (key, pattern, false),
+            _ => return Ok(invalid("DELETEMANY")),
+        };
+        let address = canonical_address(ctx.address);
+        let mut lines: Vec<String> = Vec::new();
+        let active_servers = {
+            let db = ctx.db.lock().unwrap();
+            if let Some(denied) = Self::authorize(&db, key, Role::Admin)? {
+                return Ok(denied);
+            }
+            servers_repo::list_active(&db)?
+                .into_iter()
+                .map(|server| (server.ip_address, server.port))
+                .collect::<Vec<_>>()
+        };
+        let (verb, summary) = if dry_run {
+            ("WOULD_DELETE", "200 DRY_RUN")
+        } else {
+            ("DELETED", "200 DELETED")
+        };
+        let mut count = 0;
+        let label = if ctx.is_master { "master" } else { "self" };
+        for name in ctx.storage.list()? {
+            if !glob_match(pattern, &name) {
+                continue;
+            }
+            if !dry_run && let Err(e) = ctx.storage.delete(&name) {
+                eprintln!("Could not delete {}: {}", name, e);
+                continue;
             }
+            lines.push(format!("{} {} {}", verb, label, name));
+            count += 1;
         }
-        Some("list") => {
-            list_handler()?;
+        if ctx.is_master {
+            let flag = if dry_run { "--dry-run" } else { "--confirm" };
+            for (ip, port) in &active_servers {
+                let node = format!("{}:{}", ip, port);
+                let reached = Self::connect_peer(ip, port).and_then(|mut streaming| {
+                    writeln!(streaming, "DELETEMANY {} {} {}", key, pattern, flag)?;
+                    let mut reached = false;
+                    for line in BufReader::new(streaming).lines() {
+                        let line = line?;
+                        if let Some(name) = line
+                            .strip_prefix(verb)
+                            .and_then(|rest| rest.strip_prefix(" self "))
+                        {
+                            lines.push(format!("{} {} {}", verb, node, name));
+                            count += 1;
+                        } else if line.starts_with(summary) {
+                            reached = true;
+                        }
+                    }
+                    Ok(reached)
+                });
+                if !matches!(reached, Ok(true)) {
+                    lines.push(format!("NODE {} UNREACHABLE", node));
+                }
+            }
         }
-        Some("leave") => {
-            leave_handler()?;
+        if !dry_run && count > 0 {
+            Self::record_event(&ctx.db.lock().unwrap(), "DELETEMANY", address, pattern);
         }
-        Some("serve") => {
-            run_server()?;
+        lines.push(format!("{} {}", summary, count));
+        Ok(Response::new(lines))
+    }
+
+    // Last lines of LOG_FILE, which `serve --daemon` sets to where it logs.
+    fn tail_log_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let (key, count) = match arguments(line)[..] {
+            [key] => (key, DEFAULT_TAIL_LINES),
+            [key, "--lines", count] => match count.parse() {
+                Ok(count) => (key, count),
+                Err(_) => return Ok(invalid("TAILLOG")),
+            },
+            _ => return Ok(invalid("TAILLOG")),
+        };
+        if let Some(denied) = Self::authorize(&ctx.db.lock().unwrap(), key, Role::Admin)? {
+            return Ok(denied);
         }
-        Some("help") | Some("--help") => {
-            print_usage();
+        let Ok(path) = env::var("LOG_FILE") else {
+            return Ok(Response::line(
+                "404 NO_LOG_FILE file logging is not enabled",
+            ));
+        };
+        // --lines comes from the client, so it must not size anything unchecked.
+        let count = count.min(MAX_TAIL_LINES);
+        let mut tail = VecDeque::new();
+        for line in BufReader::new(fs::File::open(&path)?).lines() {
+            tail.push_back(line?);
+            if tail.len() > count {
+                tail.pop_front();
+            }
         }
-        _ => print_usage(),
+        let mut lines: Vec<String> = tail.into();
+        lines.push("DONE".to_string());
+        Ok(Response::new(lines))
     }
-    Ok(())
-}
 
-fn run_server() -> Result<(), Box<dyn Error>> {
-    let server = Server::connect()?;
-    server.run()?;
-    Ok(())
-}
+    fn conn_stats_command(_line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let stats = ctx.connections;
+        Ok(Response::new(vec![
+            format!("LIVE {}", stats.live.load(Ordering::SeqCst)),
+            format!("PEAK {}", stats.peak.load(Ordering::SeqCst)),
+            format!("HANDLED {}", stats.handled.load(Ordering::SeqCst)),
+            format!("SUBSCRIBERS {}", stats.subscribers.load(Ordering::SeqCst)),
+        ]))
+    }
 
-fn join_handler(ip_addr: &str) -> Result<(), Box<dyn Error>> {
-    Server::join(ip_addr)?;
-    Ok(())
-}
+    // Unauthenticated liveness probe for load balancers.
+    fn health_command(_line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let healthy = match ctx.db.lock() {
+            Ok(db) => db
+                .query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+                .is_ok(),
+            Err(_) => false,
+        };
+        Ok(Response::line(if healthy {
+            "200 OK"
+        } else {
+            "503 SERVICE_UNAVAILABLE"
+        }))
+    }
 
-fn list_handler() -> Result<(), Box<dyn Error>> {
-    Server::list()?;
-    Ok(())
-}
+    fn commands_command(_line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        Ok(Response::new(
+            ctx.registry.verbs().map(|verb| verb.to_string()).collect(),
+        ))
+    }
 
-fn leave_handler() -> Result<(), Box<dyn Error>> {
-    Server::leave()?;
-    Ok(())
-}
+    // Only ever dispatched inside a session, which is already what it asks for.
+    fn session_command(line: &str, _ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        if !arguments(line).is_empty() {
+            return Ok(invalid("SESSION"));
+        }
+        Ok(Response::line("200 OK"))
+    }
 
-fn print_usage() {
-    println!("\n||=======================================================================");
-    println!("|| Usage:");
-    println!("||=======================================================================");
-    println!("||  * serve                                  - Start the server");
-    println!("||  * join <ip_address:port> <master_key>    - Join the running network");
-    println!("||  * leave                                  - Leave the network");
-    println!("||  * list                                   - List all active servers");
-    println!("||  * help                                   - Show this message");
-    println!("=========================================================================");
+    fn subscribe_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key] = arguments(line)[..] else {
+            return Ok(invalid("SUBSCRIBE"));
+        };
+        if let Some(denied) = Self::authorize(&ctx.db.lock().unwrap(), key, Role::ReadOnly)? {
+            return Ok(denied);
+        }
+        Ok(Response {
+            lines: vec!["200 SUBSCRIBED".to_string()],
+            stream: Some(ctx.events.subscribe()),
+            subscription: true,
+        })
+    }
+
+    // Slaves report their own storage; the master adds every active slave's
+    // report to its own and a swarm total.
+    fn usage_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key] = arguments(line)[..] else {
+            return Ok(invalid("USAGE"));
+        };
+        let mut lines: Vec<String> = Vec::new();
+        let active_servers = {
+            let db = ctx.db.lock().unwrap();
+            if let Some(denied) = Self::authorize(&db, key, Role::ReadOnly)? {
+                return Ok(denied);
+            }
+            servers_repo::list_active(&db)?
+                .into_iter()
+                .map(|server| (server.ip_address, server.port))
+                .collect::<Vec<_>>()
+        };
+        let (own_files, own_bytes) = ctx.storage.usage()?;
+        if !ctx.is_master {
+            return Ok(Response::line(&format!(
+                "NODE self {} {}",
+                own_files, own_bytes
+            )));
+        }
+
+        lines.push(format!("NODE master {} {}", own_files, own_bytes));
+        let (mut total_files, mut total_bytes) = (own_files, own_bytes);
+        for (ip, port) in &active_servers {
+            let mut reply = String::new();
+            let result = Self::connect_peer(ip, port).and_then(|mut streaming| {
+                writeln!(streaming, "USAGE {}", key)?;
+                BufReader::new(streaming).read_line(&mut reply)
+            });
+            let parts: Vec<&str> = reply.split_whitespace().collect();
+            let usage = match (&result, parts.as_slice()) {
+                (Ok(_), ["NODE", _, files, bytes]) => {
+                    files.parse::<u64>().ok().zip(bytes.parse::<u64>().ok())
+                }
+                _ => None,
+            };
+            match usage {
+                Some((files, bytes)) => {
+                    total_files += files;
+                    total_bytes += bytes;
+                    lines.push(format!("NODE {}:{} {} {}", ip, port, files, bytes));
+                }
+                None => lines.push(format!("NODE {}:{} UNREACHABLE", ip, port)),
+            }
+        }
+        lines.push(format!("TOTAL {} {}", total_files, total_bytes));
+        Ok(Response::new(lines))
+    }
+
+    // Marks one node as gone, by id or ip:port, without it having to LEAVE.
+    fn evict_command(line: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        let [key, node] = arguments(line)[..] else {
+            return Ok(invalid("EVICT"));
+        };
+        let address = canonical_address(ctx.address);
+        let db = ctx.db.lock().unwrap();
+        if let Some(denied) = Self::authorize(&db, key, Role::Admin)? {
+            return Ok(denied);
+        }
+        let found = match node.parse::<i64>() {
+            Ok(id) => servers_repo::find_by_id(&db, id)?,
+            Err(_) => match node.parse::<SocketAddr>().map(canonical_address) {
+                Ok(node) => servers_repo::find_by_address(&db, node)?,
+                Err(_) => None,
+            },
+        };
+        let Some(server) = found else {
+            return Ok(Response::line("404 NOT_FOUND"));
+        };
+        let (id, node) = (server.id, server.address());
+        if server.has_left {
+            return Ok(Response::line(&format!("200 ALREADY_LEFT {} {}", id, node)));
+        }
+        servers_repo::mark_left(&db, id)?;
+        info!("🚪 Evicted node {} ({})", id, node);
+        Self::record_event(&db, "EVICT", address, &format!("{} {}", id, node));
+        ctx.events.publish(format!("EVENT EVICT {}", node));
+        Ok(Response::line(&format!("200 EVICTED {} {}", id, node)))
+    }
+
+    fn unknown_command(verb: &str, ctx: &Ctx) -> Result<Response, Box<dyn Error>> {
+        debug!("Unknown command {:?} from {}", verb, ctx.address);
+        let line = match Self::suggest_command(verb, ctx.registry) {
+            Some(suggestion) => format!(
+                "400 UNKNOWN_COMMAND unknown command '{}'; did you mean '{}'?",
+                verb, suggestion
+            ),
+            None => format!("400 UNKNOWN_COMMAND unknown command '{}'", verb),
+        };
+        Ok(Response::line(&line))
+    }
+
+    // Keeps a slave subscribed to its master's event stream, reconnecting with
+    // exponential backoff whenever the link drops. Stops once the node leaves.
+    fn supervise_master_link() {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let config = match Self::verify_config() {
+                Ok(Some(config)) => config,
+                Ok(None) => {
+                    info!("No longer part of a swarm, closing the master link.");
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("⚠️ {}, retrying in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    continue;
+                }
+            };
+            let master = format!("{}:{}", config.master_ip_address, config.master_port);
+            match Self::follow_master(&master, &Self::client_key(&config), &mut backoff) {
+                Ok(()) => eprintln!(
+                    "Lost the link to master {}, reconnecting in {:?}",
+                    master, backoff
+                ),
+                Err(e) => eprintln!(
+                    "Could not reach master {}: {}, retrying in {:?}",
+                    master, e, backoff
+                ),
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    }
+
+    // Subscribes to the master and logs its events until the connection closes.
+    // The link is quiet between events, so instead of a read timeout it relies on TCP
+    // keepalive to notice a master that vanished without closing the connection.
+    fn follow_master(master: &str, key: &str, backoff: &mut Duration) -> io::Result<()> {
+        let (ip, port) = master
+            .rsplit_once(':')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing port"))?;
+        let mut stream = Self::connect_peer(ip.trim_matches(['[', ']']), port)?;
+        if let Err(e) = Self::set_keepalive(&stream) {
+            eprintln!("Could not enable keepalive on the master link: {}", e);
+        }
+        writeln!(stream, "SUBSCRIBE {}", key)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut response = String::new();
+        read_reply(&mut reader, &mut response)?;
+        stream.set_read_timeout(None)?;
+        if !response.starts_with("200") {
+            return Err(io::Error::other(format!(
+                "subscription refused: {}",
+                response.trim()
+            )));
+        }
+        info!("🔗 Linked to master at {}", master);
+        *backoff = Duration::from_secs(1);
+        for line in reader.lines() {
+            debug!("Master event: {}", line?);
+        }
+        Ok(())
+    }
+
+    // Runs EVENT_HOOK once per swarm event as `<hook> <KIND> [details...]`, with
+    // SWARM_EVENT set to the whole event line. At most MAX_HOOKS run at once; further
+    // events wait for one to finish.
+    fn run_hooks(events: &EventBus, hook: &str) {
+        let max_hooks = env_setting("MAX_HOOKS", DEFAULT_MAX_HOOKS).0.max(1);
+        let mut running: Vec<(String, std::process::Child)> = Vec::new();
+        loop {
+            for event in events.subscribe() {
+                let args: Vec<&str> = event.split(' ').skip(1).collect();
+                while running.len() >= max_hooks {
+                    let (event, mut child) = running.remove(0);
+                    Self::reap_hook(hook, &event, child.wait());
+                }
+                running.retain_mut(|(event, child)| match child.try_wait() {
+                    Ok(None) => true,
+                    Ok(Some(status)) => {
+                        Self::reap_hook(hook, event, Ok(status));
+                        false
+                    }
+                    Err(e) => {
+                        Self::reap_hook(hook, event, Err(e));
+                        false
+                    }
+                });
+                match std::process::Command::new(hook)
+                    .args(&args)
+                    .env("SWARM_EVENT", &event)
+                    .spawn()
+                {
+                    Ok(child) => running.push((event, child)),
+                    Err(e) => eprintln!("Could not run hook {} for {}: {}", hook, event, e),
+                }
+            }
+            // The bus drops subscribers that fall too far behind; pick up from here.
+            eprintln!("Event hook {} fell behind, some events were skipped", hook);
+        }
+    }
+
+    fn reap_hook(hook: &str, event: &str, status: io::Result<std::process::ExitStatus>) {
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("Hook {} for {} exited with {}", hook, event, status),
+            Err(e) => eprintln!("Could not wait for hook {} for {}: {}", hook, event, e),
+        }
+    }
+
+    // Serves a read-only view of this node's storage over HTTP on HTTP_PORT: an HTML
+    // index at `/`, JSON at `/index.json` and downloads at `/files/<name>`. Every
+    // request needs `?token=<key>`: the swarm key, or a read or admin key from KEYS_FILE.
+    // Requests share `connections` with the command listener, so MAX_CONNECTIONS and
+    // ACCEPT_RATE bound both together.
+    fn serve_http(
+        listener: TcpListener,
+        db: Arc<Mutex<Connection>>,
+        storage: Arc<dyn Storage>,
+        connections: Arc<ConnStats>,
+        accept_limit: Option<Arc<Mutex<TokenBucket>>>,
+    ) {
+        let max_connections = Self::max_connections();
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            Self::pace_accept(accept_limit.as_deref());
+            let guard = ConnectionGuard(connections.clone());
+            if connections.enter() >= max_connections {
+                debug!("Rejecting HTTP request: connection limit reached");
+                let busy = HttpResponse::new(503, "text/plain", b"busy\n".to_vec());
+                let _ = Self::write_http(&mut stream, &busy);
+                continue;
+            }
+            let db = db.clone();
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let _guard = guard;
+                if let Err(e) = Self::handle_http(stream, &db, storage.as_ref()) {
+                    debug!("HTTP request failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn handle_http(
+        mut stream: TcpStream,
+        db: &Mutex<Connection>,
+        storage: &dyn Storage,
+    ) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Self::idle_timeout())?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // Only If-None-Match is used, but every header is read so the client sees a
+        // clean close.
+        let mut if_none_match = None;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            if let Some((name, value)) = header.split_once(':')
+                && name.trim().eq_ignore_ascii_case("If-None-Match")
+            {
+                if_none_match = Some(value.trim().to_string());
+            }
+            header.clear();
+        }
+        let response = match request.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", target, _] => {
+                match Self::http_response(target, if_none_match.as_deref(), db, storage) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        eprintln!("HTTP request for {} failed: {}", target, e);
+                        HttpResponse::new(500, "text/plain", b"internal error\n".to_vec())
+                    }
+                }
+            }
+            _ => HttpResponse::new(405, "text/plain", b"only GET is supported\n".to_vec()),
+        };
+        Self::write_http(&mut stream, &response)?;
+        Ok(())
+    }
+
+    fn write_http(stream: &mut TcpStream, response: &HttpResponse) -> io::Result<()> {
+        let reason = match response.status {
+            200 => "OK",
+            304 => "Not Modified",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let etag = response
+            .etag
+            .as_ref()
+            .map(|etag| format!("ETag: {}\r\n", etag))
+            .unwrap_or_default();
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            response.status,
+            reason,
+            response.content_type,
+            response.body.len(),
+            etag
+        )?;
+        stream.write_all(&response.body)
+    }
+
+    // The reply to a request target such as `/files/a.txt?token=k`. A download whose
+    // ETag, a hash of its content, appears in `if_none_match` gets an empty 304 instead.
+    fn http_response(
+        target: &str,
+        if_none_match: Option<&str>,
+        db: &Mutex<Connection>,
+        storage: &dyn Storage,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let token = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(percent_decode)
+            .unwrap_or_default();
+        if let Some(denied) = Self::authorize(&db.lock().unwrap(), &token, Role::ReadOnly)? {
+            let status = if denied.lines[0] == "403 FORBIDDEN" {
+                403
+            } else {
+                401
+            };
+            return Ok(HttpResponse::new(
+                status,
+                "text/plain",
+                b"a valid token is required\n".to_vec(),
+            ));
+        }
+        let mut files = storage.list()?;
+        files.sort();
+        if let Some(name) = path.strip_prefix("/files/") {
+            // Only names the storage lists are served, so `..` and the like cannot escape it.
+            let name = percent_decode(name);
+            if !files.contains(&name) {
+                return Ok(HttpResponse::new(
+                    404,
+                    "text/plain",
+                    b"no such file\n".to_vec(),
+                ));
+            }
+            let body = storage.read(&name)?;
+            let etag = format!("\"{}\"", Self::content_hash(&body));
+            let unchanged = if_none_match.is_some_and(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+            });
+            let mut response = if unchanged {
+                HttpResponse::new(304, "application/octet-stream", Vec::new())
+            } else {
+                HttpResponse::new(200, "application/octet-stream", body)
+            };
+            response.etag = Some(etag);
+            return Ok(response);
+        }
+        match path {
+            "/index.json" => {
+                let names: Vec<String> = files.iter().map(|name| json_string(name)).collect();
+                let body = format!("{{\"files\":[{}]}}\n", names.join(","));
+                Ok(HttpResponse::new(
+                    200,
+                    "application/json",
+                    body.into_bytes(),
+                ))
+            }
+            "/" => {
+                let token = percent_encode(&token);
+                let mut body = String::from("<!DOCTYPE html>\n<title>Files</title>\n<ul>\n");
+                for name in &files {
+                    body.push_str(&format!(
+                        "<li><a href=\"/files/{}?token={}\">{}</a></li>\n",
+                        percent_encode(name),
+                        token,
+                        html_escape(name)
+                    ));
+                }
+                body.push_str("</ul>\n");
+                Ok(HttpResponse::new(
+                    200,
+                    "text/html; charset=utf-8",
+                    body.into_bytes(),
+                ))
+            }
+            _ => Ok(HttpResponse::new(
+                404,
+                "text/plain",
+                b"not found\n".to_vec(),
+            )),
+        }
+    }
+
+    // Turns on TCP keepalive so the OS notices peers that vanished without closing the
+    // connection (NAT timeouts, partitions). KEEPALIVE_IDLE_SECS=0 leaves it off.
+    fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+        let idle = env_setting("KEEPALIVE_IDLE_SECS", DEFAULT_KEEPALIVE_IDLE_SECS).0;
+        if idle == 0 {
+            return Ok(());
+        }
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+        // Not every platform lets the probe interval and count be tuned; elsewhere the
+        // OS defaults apply.
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "windows"
+        ))]
+        let keepalive = keepalive.with_interval(Duration::from_secs(
+            env_setting("KEEPALIVE_INTERVAL_SECS", DEFAULT_KEEPALIVE_INTERVAL_SECS).0,
+        ));
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd"
+        ))]
+        let keepalive =
+            keepalive.with_retries(env_setting("KEEPALIVE_RETRIES", DEFAULT_KEEPALIVE_RETRIES).0);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+
+    // Answers admin commands read from `input` against the local database, for
+    // debugging a running node from its terminal. Shares the database mutex with the
+    // connection handlers, so a command waits its turn like any request.
+    fn repl<R: BufRead, W: Write>(
+        input: R,
+        output: &mut W,
+        db: &Mutex<Connection>,
+        connections: &ConnStats,
+        is_master: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let result = Self::repl_command(line, db, connections, is_master);
+            match result {
+                Ok(lines) => {
+                    for line in lines {
+                        writeln!(output, "{}", line)?;
+                    }
+                }
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn repl_command(
+        line: &str,
+        db: &Mutex<Connection>,
+        connections: &ConnStats,
+        is_master: bool,
+    ) -> DBResult<Vec<String>> {
+        match line {
+            "list" => {
+                let db = db.lock().unwrap();
+                Ok(servers_repo::list_all(&db)?
+                    .iter()
+                    .map(|server| {
+                        format!(
+                            "{} {}{}",
+                            server.id,
+                            server.address(),
+                            if server.has_left { " (left)" } else { "" }
+                        )
+                    })
+                    .collect())
+            }
+            "status" => {
+                let db = db.lock().unwrap();
+                let (active, _) = servers_repo::counts(&db)?;
+                let swarm_id = Self::meta_get(&db, "swarm_id")?;
+                Ok(vec![
+                    format!("role {}", if is_master { "master" } else { "slave" }),
+                    format!("swarm {}", swarm_id.as_deref().unwrap_or("unknown")),
+                    format!("servers {}", active),
+                ])
+            }
+            "stats" => Ok(vec![
+                format!("live {}", connections.live.load(Ordering::SeqCst)),
+                format!("peak {}", connections.peak.load(Ordering::SeqCst)),
+                format!("handled {}", connections.handled.load(Ordering::SeqCst)),
+            ]),
+            // Forgets nodes that have left, which otherwise stay in the table.
+            "purge" => servers_repo::purge_left(&db.lock().unwrap())
+                .map(|purged| vec![format!("purged {}", purged)]),
+            "help" => Ok(vec!["commands: list, status, stats, purge".to_string()]),
+            other => Ok(vec![format!("unknown command '{}', try help", other)]),
+        }
+    }
+
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_master {
+            thread::spawn(Self::supervise_master_link);
+        }
+        if REPL.load(Ordering::Relaxed) {
+            let database = self.database.clone();
+            let connections = self.connections.clone();
+            let is_master = self.is_master;
+            thread::spawn(move || {
+                let stdin = io::stdin().lock();
+                if let Err(e) =
+                    Self::repl(stdin, &mut io::stdout(), &database, &connections, is_master)
+                {
+                    eprintln!("REPL stopped: {}", e);
+                }
+            });
+        }
+        let accept_limit = Self::accept_limit();
+        let http_port = env_setting("HTTP_PORT", 0u16).0;
+        if http_port != 0 {
+            let listener = TcpListener::bind((Self::bind_ip().0, http_port))?;
+            info!("🌐 HTTP file index at http://{}", listener.local_addr()?);
+            let database = self.database.clone();
+            let storage = self.storage.clone();
+            let connections = self.connections.clone();
+            let accept_limit = accept_limit.clone();
+            thread::spawn(move || {
+                Self::serve_http(listener, database, storage, connections, accept_limit)
+            });
+        }
+        if let Ok(hook) = env::var("EVENT_HOOK") {
+            let events = self.events.clone();
+            thread::spawn(move || Self::run_hooks(&events, &hook));
+        }
+        // Printed even with --quiet: scripts and tests wait for this line instead of
+        // sleeping before they connect.
+        println!("READY {}", self.listener.local_addr()?);
+        io::stdout().flush()?;
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, address)) => {
+                    debug!("Accepted connection from {}", address);
+                    // Pausing here leaves later clients queued in the listen backlog.
+                    Self::pace_accept(accept_limit.as_deref());
+                    if let Err(e) = Self::set_keepalive(&stream) {
+                        eprintln!("Could not enable keepalive for {}: {}", address, e);
+                    }
+                    let guard = ConnectionGuard(self.connections.clone());
+                    if self.connections.enter() >= self.max_connections {
+                        debug!("Rejecting {}: connection limit reached", address);
+                        let _ = writeln!(stream, "503 BUSY");
+                        continue;
+                    }
+                    let database = self.database.clone();
+                    let is_master = self.is_master;
+                    let events = self.events.clone();
+                    let storage = self.storage.clone();
+                    let connections = self.connections.clone();
+                    let banner = self.banner.clone();
+                    let registry = self.registry.clone();
+                    thread::spawn(move || {
+                        let _guard = guard;
+                        if let Some(banner) = banner
+                            && let Err(e) = writeln!(stream, "{}", banner)
+                        {
+                            eprintln!("Error handling connection: {}", e);
+                            return;
+                        }
+                        let local_address = match stream.local_addr() {
+                            Ok(local_address) => local_address,
+                            Err(e) => {
+                                eprintln!("Error handling connection: {}", e);
+                                return;
+                            }
+                        };
+                        let ctx = Ctx {
+                            db: &database,
+                            address,
+                            local_address,
+                            is_master,
+                            events: &events,
+                            storage: storage.as_ref(),
+                            connections: &connections,
+                            registry: &registry,
+                        };
+                        match Self::handle_connection(stream, &ctx) {
+                            Err(e) if Self::is_timeout(e.as_ref()) => {
+                                debug!("Closing idle connection from {}", address);
+                            }
+                            Err(e) => eprintln!("Error handling connection: {}", e),
+                            Ok(()) => {}
+                        }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Connection error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Main Functions
+fn main() -> Result<(), Box<dyn Error>> {
+    dotenv::dotenv().ok();
+    let mut args: Vec<String> = env::args().collect();
+    args.retain(|arg| match arg.as_str() {
+        "--verbose" | "-v" => {
+            VERBOSITY.store(VERBOSE, Ordering::Relaxed);
+            false
+        }
+        "--quiet" | "-q" => {
+            VERBOSITY.store(QUIET, Ordering::Relaxed);
+            false
+        }
+        _ => true,
+    });
+    if let Err(e) = check_args(&args) {
+        eprintln!("error: {}", e);
+        eprintln!("Run `help` to see how each command is used.");
+        std::process::exit(USAGE_ERROR);
+    }
+    match args.get(1).map(|s| s.as_str()) {
+        Some("join") => {
+            let force = args.iter().skip(3).any(|arg| arg == "--force");
+            join_handler(&args[2], force)?;
+        }
+        Some("list") => {
+            list_handler(None)?;
+        }
+        Some("files") => {
+            list_handler(Some(&args[2]))?;
+        }
+        Some("usage") => {
+            usage_handler()?;
+        }
+        Some("count") => {
+            count_handler()?;
+        }
+        Some("evict") => {
+            evict_handler(&args[2])?;
+        }
+        Some("benchmark") => {
+            let connections = flag_value(&args, "--connections").unwrap_or(4);
+            let requests = flag_value(&args, "--requests").unwrap_or(100);
+            let command = flag_value(&args, "--command").unwrap_or("HEALTH".to_string());
+            benchmark_handler(&args[2], connections, requests, &command)?;
+        }
+        Some("dump-config") => {
+            Server::dump_config();
+        }
+        Some("doctor") => {
+            if !Server::doctor() {
+                std::process::exit(1);
+            }
+        }
+        Some("commands") => {
+            commands_handler(&args[2])?;
+        }
+        Some("leave") => {
+            leave_handler()?;
+        }
+        Some("session") => {
+            session_handler(&args[2])?;
+        }
+        Some("whoami") => {
+            whoami_handler()?;
+        }
+        Some("master") => {
+            master_handler(args.get(2).map(|arg| arg.as_str()))?;
+        }
+        Some("snapshot") => {
+            snapshot_handler(&args[2])?;
+        }
+        Some("restore") => {
+            let force = args.iter().skip(3).any(|arg| arg == "--force");
+            restore_handler(&args[2], force)?;
+        }
+        Some("serve") => {
+            ACCEPT_KEY_CHANGE.store(
+                args.iter().any(|arg| arg == "--accept-key-change"),
+                Ordering::Relaxed,
+            );
+            REPL.store(args.iter().any(|arg| arg == "--repl"), Ordering::Relaxed);
+            let pid_file = flag_value::<String>(&args, "--pid-file");
+            if args.iter().any(|arg| arg == "--daemon") {
+                let log_file = flag_value(&args, "--log-file").unwrap_or("server.log".to_string());
+                daemonize(pid_file.unwrap_or("server.pid".to_string()), &log_file)?;
+            } else {
+                run_server(pid_file)?;
+            }
+        }
+        Some("help") | Some("--help") => {
+            print_usage();
+        }
+        _ => print_usage(),
+    }
+    Ok(())
+}
+
+fn run_server(pid_file: Option<String>) -> Result<(), Box<dyn Error>> {
+    let _pid_file = match pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+    let server = Server::connect()?;
+    server.run()?;
+    Ok(())
+}
+
+// Holds the PID file for as long as the server runs and removes it on the way out,
+// whether `run` returns or the process is asked to stop.
+struct PidFile(String);
+
+impl PidFile {
+    fn create(path: String) -> Result<Self, Box<dyn Error>> {
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        let on_signal = path.clone();
+        ctrlc::set_handler(move || {
+            let _ = fs::remove_file(&on_signal);
+            std::process::exit(0);
+        })?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Restarts `serve` as a detached child in its own process group, with output going
+// to `log_file`. The child writes the PID file itself so it always names the server.
+#[cfg(unix)]
+fn daemonize(pid_file: String, log_file: &str) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command as Process, Stdio};
+
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let mut child = Process::new(env::current_exe()?);
+    child.args(["serve", "--pid-file", &pid_file]);
+    if ACCEPT_KEY_CHANGE.load(Ordering::Relaxed) {
+        child.arg("--accept-key-change");
+    }
+    child.env("LOG_FILE", fs::canonicalize(log_file)?);
+    match VERBOSITY.load(Ordering::Relaxed) {
+        QUIET => {
+            child.arg("--quiet");
+        }
+        VERBOSE => {
+            child.arg("--verbose");
+        }
+        _ => {}
+    }
+    let child = child
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()?;
+    info!(
+        "🌙 Serving in the background as PID {} (logging to {})",
+        child.id(),
+        log_file
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemonize(pid_file: String, _log_file: &str) -> Result<(), Box<dyn Error>> {
+    eprintln!("⚠️ --daemon is only supported on Unix; running in the foreground.");
+    run_server(Some(pid_file))
+}
+
+fn join_handler(ip_addrs: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    Server::join(ip_addrs, force)?;
+    Ok(())
+}
+
+fn list_handler(pattern: Option<&str>) -> Result<(), Box<dyn Error>> {
+    Server::list(pattern)?;
+    Ok(())
+}
+
+fn count_handler() -> Result<(), Box<dyn Error>> {
+    Server::count()?;
+    Ok(())
+}
+
+fn usage_handler() -> Result<(), Box<dyn Error>> {
+    Server::usage()?;
+    Ok(())
+}
+
+fn benchmark_handler(
+    ip_addr: &str,
+    connections: usize,
+    requests: usize,
+    command: &str,
+) -> Result<(), Box<dyn Error>> {
+    Server::benchmark(ip_addr, connections, requests, command)?;
+    Ok(())
+}
+
+// What a subcommand accepts: positional arguments it needs, ones it may take, and
+// options with and without a value. Numeric options must parse as a number.
+struct Subcommand {
+    name: &'static str,
+    required: &'static [&'static str],
+    optional: &'static [&'static str],
+    values: &'static [&'static str],
+    numeric: &'static [&'static str],
+    switches: &'static [&'static str],
+}
+
+const fn subcommand(name: &'static str) -> Subcommand {
+    Subcommand {
+        name,
+        required: &[],
+        optional: &[],
+        values: &[],
+        numeric: &[],
+        switches: &[],
+    }
+}
+
+const SUBCOMMANDS: &[Subcommand] = &[
+    Subcommand {
+        required: &["<ip_address:port>[,...]"],
+        switches: &["--force"],
+        ..subcommand("join")
+    },
+    subcommand("leave"),
+    subcommand("whoami"),
+    Subcommand {
+        optional: &["[ip_address:port]"],
+        ..subcommand("master")
+    },
+    subcommand("list"),
+    Subcommand {
+        required: &["<pattern>"],
+        ..subcommand("files")
+    },
+    subcommand("usage"),
+    subcommand("count"),
+    Subcommand {
+        required: &["<node_id|ip_address:port>"],
+        ..subcommand("evict")
+    },
+    Subcommand {
+        required: &["<ip_address:port>"],
+        ..subcommand("commands")
+    },
+    Subcommand {
+        required: &["<ip_address:port>"],
+        ..subcommand("session")
+    },
+    subcommand("dump-config"),
+    subcommand("doctor"),
+    Subcommand {
+        required: &["<ip_address:port>"],
+        values: &["--command"],
+        numeric: &["--connections", "--requests"],
+        ..subcommand("benchmark")
+    },
+    Subcommand {
+        required: &["<path>"],
+        ..subcommand("snapshot")
+    },
+    Subcommand {
+        required: &["<path>"],
+        switches: &["--force"],
+        ..subcommand("restore")
+    },
+    Subcommand {
+        values: &["--pid-file", "--log-file"],
+        switches: &["--daemon", "--accept-key-change", "--repl"],
+        ..subcommand("serve")
+    },
+    subcommand("help"),
+    subcommand("--help"),
+];
+
+// Exit code for a malformed command line.
+const USAGE_ERROR: i32 = 2;
+
+// Checks `args` against SUBCOMMANDS, naming the missing or unexpected argument.
+// No subcommand at all is fine: it prints the usage.
+fn check_args(args: &[String]) -> Result<(), String> {
+    let Some(name) = args.get(1) else {
+        return Ok(());
+    };
+    let Some(spec) = SUBCOMMANDS.iter().find(|spec| spec.name == name) else {
+        return Err(format!("unknown command '{}'", name));
+    };
+    let mut positional = Vec::new();
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        let needs_value = spec.values.contains(&arg.as_str());
+        let needs_number = spec.numeric.contains(&arg.as_str());
+        if needs_value || needs_number {
+            let Some(value) = rest.next() else {
+                return Err(format!("{} needs a value", arg));
+            };
+            if needs_number && value.parse::<usize>().is_err() {
+                return Err(format!("{} needs a number, got '{}'", arg, value));
+            }
+        } else if arg.starts_with("--") && !spec.switches.contains(&arg.as_str()) {
+            return Err(format!("unknown option '{}' for '{}'", arg, name));
+        } else if !arg.starts_with("--") {
+            positional.push(arg);
+        }
+    }
+    if let Some(missing) = spec.required.get(positional.len()) {
+        return Err(format!("'{}' needs {}", name, missing));
+    }
+    if let Some(extra) = positional.get(spec.required.len() + spec.optional.len()) {
+        return Err(format!("unexpected argument '{}' for '{}'", extra, name));
+    }
+    Ok(())
+}
+
+// Value following `--name` on the command line, if present and parseable.
+fn flag_value<T: FromStr>(args: &[String], name: &str) -> Option<T> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.parse::<T>().ok())
+}
+
+fn evict_handler(node: &str) -> Result<(), Box<dyn Error>> {
+    Server::evict(node)?;
+    Ok(())
+}
+
+fn commands_handler(ip_addr: &str) -> Result<(), Box<dyn Error>> {
+    Server::commands(ip_addr)?;
+    Ok(())
+}
+
+fn leave_handler() -> Result<(), Box<dyn Error>> {
+    Server::leave()?;
+    Ok(())
+}
+
+fn master_handler(ip_addr: Option<&str>) -> Result<(), Box<dyn Error>> {
+    Server::master(ip_addr)?;
+    Ok(())
+}
+
+fn session_handler(ip_addr: &str) -> Result<(), Box<dyn Error>> {
+    Server::session(ip_addr)?;
+    Ok(())
+}
+
+fn whoami_handler() -> Result<(), Box<dyn Error>> {
+    Server::whoami()?;
+    Ok(())
+}
+
+fn snapshot_handler(path: &str) -> Result<(), Box<dyn Error>> {
+    Server::snapshot(path)?;
+    Ok(())
+}
+
+fn restore_handler(path: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    Server::restore(path, force)?;
+    Ok(())
+}
+
+fn print_usage() {
+    println!("\n||=======================================================================");
+    println!("|| Usage:");
+    println!("||=======================================================================");
+    println!("||  * serve                                  - Start the server");
+    println!("||      [--daemon] [--pid-file PATH] [--log-file PATH] [--accept-key-change]");
+    println!("||      [--repl] (admin commands on stdin: list, status, stats, purge)");
+    println!("||  * join <ip_address:port>[,...]           - Join the first master that accepts");
+    println!("||      [--force] (even if this node is a master)");
+    println!("||  * leave                                  - Leave the network");
+    println!("||  * whoami                                 - Show this node's role and swarm");
+    println!("||  * master [ip_address:port]               - Ask a node who the master is");
+    println!("||  * list                                   - List all active servers");
+    println!("||  * files <pattern>                        - List files matching a glob or prefix");
+    println!("||  * usage                                  - Show storage used per node");
+    println!("||  * count                                  - Show how many nodes are in the swarm");
+    println!("||  * evict <node_id|ip_address:port>        - Remove a node from the swarm");
+    println!("||  * commands <ip_address:port>             - List commands a server supports");
+    println!("||  * session <ip_address:port>              - Send commands read from stdin");
+    println!("||  * dump-config                            - Print the effective configuration");
+    println!("||  * doctor                                 - Check this node's setup for problems");
+    println!("||  * benchmark <ip_address:port>            - Load test a server");
+    println!("||      [--connections N] [--requests M] [--command VERB]");
+    println!("||  * snapshot <path>                        - Export the database to a file");
+    println!("||  * restore <path> [--force]               - Load a snapshot into the database");
+    println!("||  * help                                   - Show this message");
+    println!("||=======================================================================");
+    println!("|| Flags:");
+    println!("||  --verbose, -v                            - Print debug output");
+    println!("||  --quiet, -q                              - Print errors only");
+    println!("=========================================================================");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "test-key";
+
+    // File names and sizes, minus any that were deleted.
+    struct MemStorage {
+        files: Vec<(String, u64)>,
+        deleted: Mutex<Vec<String>>,
+    }
+
+    impl MemStorage {
+        fn present(&self) -> Vec<(String, u64)> {
+            let deleted = self.deleted.lock().unwrap();
+            self.files
+                .iter()
+                .filter(|(name, _)| !deleted.contains(name))
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl Storage for MemStorage {
+        fn list(&self) -> io::Result<Vec<String>> {
+            Ok(self.present().into_iter().map(|(name, _)| name).collect())
+        }
+
+        fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+            match self.present().into_iter().find(|(known, _)| known == name) {
+                Some((_, size)) => Ok(vec![b'x'; size as usize]),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn usage(&self) -> io::Result<(u64, u64)> {
+            let present = self.present();
+            let bytes = present.iter().map(|(_, size)| size).sum();
+            Ok((present.len() as u64, bytes))
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            self.deleted.lock().unwrap().push(name.to_string());
+            Ok(())
+        }
+    }
+
+    // A master node backed by an in-memory database and storage.
+    struct TestNode {
+        db: Mutex<Connection>,
+        events: EventBus,
+        storage: MemStorage,
+        connections: Arc<ConnStats>,
+        registry: Registry,
+        is_master: bool,
+    }
+
+    impl TestNode {
+        fn new() -> Self {
+            Self::open(":memory:")
+        }
+
+        fn open(path: &str) -> Self {
+            let db = Server::open_db(path).unwrap();
+            Server::seed_key(&db, KEY, false).unwrap();
+            TestNode {
+                db: Mutex::new(db),
+                events: EventBus::new(),
+                storage: MemStorage {
+                    files: Vec::new(),
+                    deleted: Mutex::new(Vec::new()),
+                },
+                connections: Arc::new(ConnStats::default()),
+                registry: Registry::builtin(),
+                is_master: true,
+            }
+        }
+
+        fn send(&self, address: &str, line: &str) -> Response {
+            let ctx = Ctx {
+                db: &self.db,
+                address: address.parse().unwrap(),
+                local_address: "127.0.0.1:8777".parse().unwrap(),
+                is_master: self.is_master,
+                events: &self.events,
+                storage: &self.storage,
+                connections: &self.connections,
+                registry: &self.registry,
+            };
+            Server::process_with_retry(line, &ctx).unwrap()
+        }
+
+        // Lines of the response, including any streamed ones. Not for SUBSCRIBE,
+        // whose stream never ends.
+        fn run(&self, address: &str, line: &str) -> Vec<String> {
+            let response = self.send(address, line);
+            let mut lines = response.lines;
+            lines.extend(response.stream.into_iter().flatten());
+            lines
+        }
+
+        fn server_count(&self) -> i64 {
+            self.db
+                .lock()
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM servers", [], |row| row.get(0))
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn join_registers_new_server() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        assert_eq!(response[0], "Swam has been joined!");
+        assert!(response[1].starts_with("SWARM "));
+        assert_eq!(node.server_count(), 1);
+    }
+
+    // TCP will not carry two live connections from one address to the same master, so
+    // concurrent joins from a single address are driven through the registry.
+    #[test]
+    fn concurrent_joins_from_one_address_make_one_row() {
+        let node = TestNode::new();
+        let responses: Vec<Vec<String>> = thread::scope(|scope| {
+            let joins: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| node.run("127.0.0.1:9001", &format!("JOIN {}", KEY))))
+                .collect();
+            joins.into_iter().map(|join| join.join().unwrap()).collect()
+        });
+        let joined = responses
+            .iter()
+            .filter(|response| response[0] == "Swam has been joined!")
+            .count();
+        assert_eq!(joined, 1);
+        assert_eq!(node.server_count(), 1);
+    }
+
+    #[test]
+    fn join_with_wrong_swarm_id_is_rejected() {
+        let node = TestNode::new();
+        let swarm_id = Server::seed_swarm_id(&node.db.lock().unwrap()).unwrap();
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {} not-{}", KEY, swarm_id));
+        assert_eq!(response, vec!["409 WRONG_SWARM"]);
+        assert_eq!(node.server_count(), 0);
+
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {} {}", KEY, swarm_id));
+        assert_eq!(response[1], format!("SWARM {}", swarm_id));
+        assert_eq!(node.server_count(), 1);
+    }
+
+    #[test]
+    fn join_twice_reports_duplicate() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        assert_eq!(response, vec!["Server already exists!"]);
+        assert_eq!(node.server_count(), 1);
+    }
+
+    #[test]
+    fn join_with_bad_key_is_rejected() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", "JOIN wrong-key");
+        assert_eq!(response, vec!["Authentication failed!"]);
+        assert_eq!(node.server_count(), 0);
+    }
+
+    #[test]
+    fn keys_file_roles_are_looked_up_by_hash() {
+        let keys = format!(
+            "# reporting\n{} read\n\n{} admin\n",
+            Server::hash_key("reader"),
+            Server::hash_key("operator")
+        );
+        let lookup = |key: &str| Role::lookup(&keys, &Server::hash_key(key));
+        assert_eq!(lookup("reader"), Some(Role::ReadOnly));
+        assert_eq!(lookup("operator"), Some(Role::Admin));
+        assert_eq!(lookup("stranger"), None);
+        assert!(Role::ReadOnly < Role::Admin);
+    }
+
+    #[test]
+    fn join_without_key_is_a_bad_request() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", "JOIN");
+        assert_eq!(response, vec!["400 BAD_REQUEST wrong arguments for JOIN"]);
+    }
+
+    #[test]
+    fn evict_marks_node_as_left() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        node.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9003", &format!("EVICT {} 127.0.0.1:9001", KEY));
+        assert_eq!(response, vec!["200 EVICTED 1 127.0.0.1:9001"]);
+
+        let active: Vec<String> = node
+            .db
+            .lock()
+            .unwrap()
+            .prepare("SELECT port FROM servers WHERE has_left = 0")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<DBResult<_>>()
+            .unwrap();
+        assert_eq!(active, vec!["9002"]);
+    }
+
+    #[test]
+    fn list_skips_unreachable_servers() {
+        let node = TestNode::new();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        node.run(&format!("127.0.0.1:{}", port), &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9002", "LIST");
+        assert_eq!(response, vec!["Collecting data from servers...", "DONE"]);
+    }
+
+    #[test]
+    fn list_gives_up_on_unresponsive_servers() {
+        let node = TestNode::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        node.run(&address.to_string(), &format!("JOIN {}", KEY));
+        // Accept the connection but never answer it.
+        let _silent = thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+
+        let started = std::time::Instant::now();
+        let response = node.run("127.0.0.1:9002", "LIST");
+        assert_eq!(response, vec!["Collecting data from servers...", "DONE"]);
+        assert!(started.elapsed() < Duration::from_secs(DEFAULT_SLAVE_TIMEOUT_SECS + 2));
+    }
+
+    #[test]
+    fn request_join_gives_up_on_a_silent_master() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let _silent = thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+
+        let started = std::time::Instant::now();
+        let result = Server::request_join(&address.to_string(), &format!("JOIN {}", KEY));
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(DEFAULT_SLAVE_TIMEOUT_SECS + 2));
+    }
+
+    #[test]
+    fn list_streams_large_listings() {
+        const FILES: usize = 10_000;
+        let node = TestNode::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        node.run(&address.to_string(), &format!("JOIN {}", KEY));
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = String::new();
+            BufReader::new(&stream).read_line(&mut query).unwrap();
+            assert_eq!(query.trim(), "FILES");
+            for i in 0..FILES {
+                writeln!(stream, "file-{}.txt", i).unwrap();
+            }
+            writeln!(stream, "DONE").unwrap();
+        });
+
+        let response = node.send("127.0.0.1:9002", "LIST");
+        // Nothing but the header is buffered up front; the listing arrives through a
+        // channel that holds at most EVENT_BUFFER names at a time.
+        assert_eq!(response.lines, vec!["Collecting data from servers..."]);
+        let streamed: Vec<String> = response.stream.unwrap().into_iter().collect();
+        assert_eq!(streamed.len(), FILES + 1);
+        assert_eq!(streamed[FILES - 1], format!("file-{}.txt", FILES - 1));
+        assert_eq!(streamed[FILES], "DONE");
+    }
+
+    #[test]
+    fn list_forwards_file_names_containing_done() {
+        let node = TestNode::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        node.run(&address.to_string(), &format!("JOIN {}", KEY));
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = String::new();
+            BufReader::new(&stream).read_line(&mut query).unwrap();
+            writeln!(stream, "DONE.txt\nundone/notes.txt\nDONE").unwrap();
+        });
+
+        let response = node.run("127.0.0.1:9002", "LIST");
+        assert_eq!(
+            response,
+            vec![
+                "Collecting data from servers...",
+                "DONE.txt",
+                "undone/notes.txt",
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn files_lists_storage_contents() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a.txt".to_string(), 1), ("b.txt".to_string(), 2)];
+        let response = node.run("127.0.0.1:9001", "FILES");
+        assert_eq!(response, vec!["a.txt", "b.txt", "DONE"]);
+    }
+
+    #[test]
+    fn files_filters_by_prefix() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![
+            ("logs/a.txt".to_string(), 1),
+            ("logs/b.txt".to_string(), 1),
+            ("notes.txt".to_string(), 1),
+        ];
+        let response = node.run("127.0.0.1:9001", "FILES logs/");
+        assert_eq!(response, vec!["logs/a.txt", "logs/b.txt", "DONE"]);
+    }
+
+    #[test]
+    fn files_filters_by_wildcard() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![
+            ("logs/2024-01.txt".to_string(), 1),
+            ("logs/2025-01.txt".to_string(), 1),
+            ("logs/2024-02.csv".to_string(), 1),
+        ];
+        let response = node.run("127.0.0.1:9001", "FILES logs/2024-*.txt");
+        assert_eq!(response, vec!["logs/2024-01.txt", "DONE"]);
+        let response = node.run("127.0.0.1:9001", "FILES logs/202?-01.txt");
+        assert_eq!(
+            response,
+            vec!["logs/2024-01.txt", "logs/2025-01.txt", "DONE"]
+        );
+    }
+
+    #[test]
+    fn deletemany_dry_run_keeps_files() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a.log".to_string(), 1), ("b.txt".to_string(), 1)];
+        let response = node.run(
+            "127.0.0.1:9001",
+            &format!("DELETEMANY {} *.log --dry-run", KEY),
+        );
+        assert_eq!(response, vec!["WOULD_DELETE master a.log", "200 DRY_RUN 1"]);
+        assert_eq!(
+            node.run("127.0.0.1:9001", "FILES"),
+            vec!["a.log", "b.txt", "DONE"]
+        );
+    }
+
+    #[test]
+    fn deletemany_confirm_removes_matching_files() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a.log".to_string(), 1), ("b.txt".to_string(), 1)];
+        let response = node.run("127.0.0.1:9001", &format!("DELETEMANY {} *.log", KEY));
+        assert_eq!(
+            response,
+            vec!["400 BAD_REQUEST wrong arguments for DELETEMANY"]
+        );
+        let response = node.run(
+            "127.0.0.1:9001",
+            &format!("DELETEMANY {} *.log --confirm", KEY),
+        );
+        assert_eq!(response, vec!["DELETED master a.log", "200 DELETED 1"]);
+        assert_eq!(node.run("127.0.0.1:9001", "FILES"), vec!["b.txt", "DONE"]);
+    }
+
+    #[test]
+    fn usage_includes_master_and_total() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a.txt".to_string(), 10), ("b.txt".to_string(), 5)];
+        let response = node.run("127.0.0.1:9001", &format!("USAGE {}", KEY));
+        assert_eq!(response, vec!["NODE master 2 15", "TOTAL 2 15"]);
+    }
+
+    #[test]
+    fn health_needs_no_key() {
+        let node = TestNode::new();
+        assert_eq!(node.run("127.0.0.1:9001", "HEALTH"), vec!["200 OK"]);
+    }
+
+    #[test]
+    fn events_records_membership_changes() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        node.run("127.0.0.1:9001", "LEAVE");
+        let response = node.run("127.0.0.1:9002", &format!("EVENTS {}", KEY));
+        assert_eq!(response.len(), 3);
+        assert!(response[0].ends_with("JOIN 127.0.0.1:9001"));
+        assert!(response[1].ends_with("LEAVE 127.0.0.1:9001"));
+        assert_eq!(response[2], "DONE");
+
+        let response = node.run("127.0.0.1:9002", &format!("EVENTS {} --limit 1", KEY));
+        assert!(response[0].ends_with("LEAVE 127.0.0.1:9001"));
+        let far_future = Server::now_secs() + 3600;
+        let response = node.run(
+            "127.0.0.1:9002",
+            &format!("EVENTS {} --since {}", KEY, far_future),
+        );
+        assert_eq!(response, vec!["DONE"]);
+    }
+
+    #[test]
+    fn slave_config_without_master_address_is_rejected() {
+        let config = Config {
+            master_ip_address: String::new(),
+            master_port: 8777,
+            slave_port: 9001,
+            master_key: None,
+            swarm_id: None,
+        };
+        assert!(Server::connect_slave(config).is_err());
+    }
+
+    #[test]
+    fn slave_config_accepts_resolvable_host_names() {
+        let mut config = Config {
+            master_ip_address: "localhost".to_string(),
+            master_port: 8777,
+            slave_port: 9001,
+            master_key: None,
+            swarm_id: None,
+        };
+        assert!(config.validate().is_ok());
+        config.master_ip_address = "no such host.invalid".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn flush_then_snapshot_contains_recent_join() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9002", &format!("FLUSH {}", KEY));
+        assert_eq!(
+            response,
+            vec!["200 FLUSHED journal_mode=memory checkpoint=not_needed"]
+        );
+
+        let path = env::temp_dir().join(format!("flush-snapshot-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        node.db
+            .lock()
+            .unwrap()
+            .execute("VACUUM INTO ?1", [path.to_str().unwrap()])
+            .unwrap();
+        let snapshot = Connection::open(&path).unwrap();
+        let servers: i64 = snapshot
+            .query_row("SELECT COUNT(*) FROM servers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(servers, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flush_checkpoints_a_wal_database() {
+        let path = env::temp_dir().join(format!("flush-wal-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let node = TestNode::open(path.to_str().unwrap());
+        let response = node.run("127.0.0.1:9002", &format!("FLUSH {}", KEY));
+        assert_eq!(
+            response,
+            vec!["200 FLUSHED journal_mode=delete checkpoint=not_needed"]
+        );
+
+        node.db
+            .lock()
+            .unwrap()
+            .pragma_update(None, "journal_mode", "WAL")
+            .unwrap();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9002", &format!("FLUSH {}", KEY));
+        let counts: Vec<&str> = response[0].split(' ').collect();
+        assert_eq!(counts[..3], ["200", "FLUSHED", "journal_mode=wal"]);
+        let frames = counts[3].strip_prefix("wal_frames=").unwrap();
+        assert_ne!(frames, "0");
+        assert_eq!(counts[4], format!("checkpointed={}", frames));
+        drop(node);
+        let _ = fs::remove_file(format!("{}-wal", path.display()));
+        let _ = fs::remove_file(format!("{}-shm", path.display()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn join_is_refused_by_slaves() {
+        let mut node = TestNode::new();
+        node.is_master = false;
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        assert_eq!(response, vec!["409 NOT_MASTER"]);
+        let swarm_id = Server::meta_get(&node.db.lock().unwrap(), "swarm_id").unwrap();
+        assert_eq!(swarm_id, None);
+        assert_eq!(node.server_count(), 0);
+    }
+
+    #[test]
+    fn leave_twice_succeeds_both_times() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        assert_eq!(
+            node.run("127.0.0.1:9001", "LEAVE"),
+            vec!["Swam has been left!"]
+        );
+        assert_eq!(
+            node.run("127.0.0.1:9001", "LEAVE"),
+            vec!["200 ALREADY_LEFT"]
+        );
+    }
+
+    #[test]
+    fn leave_from_an_unknown_node_is_refused() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        let before = servers_repo::counts(&node.db.lock().unwrap()).unwrap();
+        let response = node.run("127.0.0.1:9001", "LEAVE");
+        assert_eq!(response, vec!["404 NOT_A_MEMBER"]);
+        let after = servers_repo::counts(&node.db.lock().unwrap()).unwrap();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn leave_names_the_port_the_node_joined_with() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:50123", "LEAVE 9001");
+        assert_eq!(response, vec!["Swam has been left!"]);
+        assert_eq!(
+            node.run("127.0.0.1:50124", "LEAVE 9001"),
+            vec!["200 ALREADY_LEFT"]
+        );
+        assert_eq!(
+            node.run("127.0.0.1:50125", "LEAVE x"),
+            vec!["400 BAD_REQUEST wrong arguments for LEAVE"]
+        );
+    }
+
+    #[test]
+    fn leave_matches_join_from_mapped_address() {
+        let node = TestNode::new();
+        node.run("[::ffff:127.0.0.1]:9001", &format!("JOIN {}", KEY));
+        let response = node.run("127.0.0.1:9001", "LEAVE");
+        assert_eq!(response, vec!["Swam has been left!"]);
+        assert_eq!(node.server_count(), 1);
+    }
+
+    #[test]
+    fn open_db_canonicalizes_stored_addresses() {
+        let path = env::temp_dir().join(format!("canonical-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let db = Server::open_db(path.to_str().unwrap()).unwrap();
+        db.execute(
+            "INSERT INTO servers (ip_address, port) VALUES ('::ffff:10.0.0.5', '9001')",
+            [],
+        )
+        .unwrap();
+        drop(db);
+        let db = Server::open_db(path.to_str().unwrap()).unwrap();
+        let ip: String = db
+            .query_row("SELECT ip_address FROM servers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ip, "10.0.0.5");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn master_database_is_recognised_before_joining() {
+        let db = Server::open_db(":memory:").unwrap();
+        assert_eq!(Server::master_swarm(&db).unwrap(), None);
+        let swarm_id = Server::seed_swarm_id(&db).unwrap();
+        assert_eq!(Server::master_swarm(&db).unwrap(), Some(swarm_id));
+    }
+
+    #[test]
+    fn master_check_leaves_the_database_file_alone() {
+        let path = env::temp_dir().join(format!("master-check-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path_str = path.to_str().unwrap();
+        assert_eq!(Server::master_swarm_at(path_str).unwrap(), None);
+        assert!(!path.exists());
+
+        let db = Connection::open(&path).unwrap();
+        db.execute("CREATE TABLE unrelated (id INTEGER)", [])
+            .unwrap();
+        drop(db);
+        let before = fs::read(&path).unwrap();
+        assert_eq!(Server::master_swarm_at(path_str).unwrap(), None);
+        assert_eq!(fs::read(&path).unwrap(), before);
+        fs::remove_file(&path).unwrap();
+
+        let db = Server::open_db(path_str).unwrap();
+        let swarm_id = Server::seed_swarm_id(&db).unwrap();
+        drop(db);
+        let before = fs::read(&path).unwrap();
+        assert_eq!(Server::master_swarm_at(path_str).unwrap(), Some(swarm_id));
+        assert_eq!(fs::read(&path).unwrap(), before);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn join_falls_back_to_next_candidate() {
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = String::new();
+            BufReader::new(&stream).read_line(&mut command).unwrap();
+            assert_eq!(command.trim(), format!("JOIN {}", KEY));
+            writeln!(stream, "Swam has been joined!\nSWARM swarm-1").unwrap();
+        });
+
+        let joined = Server::join_first(&[&down, &up], &format!("JOIN {}", KEY)).unwrap();
+        assert_eq!(joined.0, up);
+        assert_eq!(joined.2.as_deref(), Some("swarm-1"));
+    }
+
+    #[test]
+    fn snapshot_restores_every_table() {
+        let scratch = env::temp_dir().join(format!("roundtrip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(&scratch).unwrap();
+        let original = TestNode::open(scratch.join("original.db").to_str().unwrap());
+        original.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        original.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        original.run("127.0.0.1:9002", "LEAVE");
+        let snapshot = scratch.join("snapshot.db");
+        let snapshot = snapshot.to_str().unwrap();
+        let dump = |db: &Connection| -> Vec<String> {
+            let mut rows = Vec::new();
+            for query in [
+                "SELECT id || ip_address || port || is_active || has_left FROM servers",
+                "SELECT key || '=' || value FROM meta ORDER BY key",
+                "SELECT id || kind || actor || details FROM events",
+            ] {
+                let mut stmt = db.prepare(query).unwrap();
+                let found = stmt.query_map([], |row| row.get::<_, String>(0)).unwrap();
+                rows.extend(found.map(|row| row.unwrap()));
+            }
+            rows
+        };
+        let before = dump(&original.db.lock().unwrap());
+        original
+            .db
+            .lock()
+            .unwrap()
+            .execute("VACUUM INTO ?1", [snapshot])
+            .unwrap();
+
+        // A node that only has its key seeded still refuses to be overwritten.
+        let reset = TestNode::open(scratch.join("reset.db").to_str().unwrap());
+        let mut db = reset.db.lock().unwrap();
+        let error = Server::restore_into(&mut db, snapshot, false).unwrap_err();
+        assert!(error.to_string().contains("1 settings"));
+        assert_eq!(Server::restore_into(&mut db, snapshot, true).unwrap(), 2);
+        assert_eq!(dump(&db), before);
+        drop(db);
+        let _ = fs::remove_dir_all(&scratch);
+    }
+
+    #[test]
+    fn master_reports_itself() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", &format!("MASTER {}", KEY));
+        assert_eq!(response, vec!["MASTER 127.0.0.1:8777"]);
+    }
+
+    const NEW_KEY: &str = "rotated-key-0123456789";
+
+    #[test]
+    fn rekey_keeps_the_old_key_during_the_grace_window() {
+        let node = TestNode::new();
+        let rekey = node.run("127.0.0.1:9001", &format!("REKEY {} {}", KEY, NEW_KEY));
+        assert_eq!(rekey, vec!["200 REKEYED 0/0"]);
+        for key in [KEY, NEW_KEY] {
+            let response = node.run("127.0.0.1:9001", &format!("COUNT {}", key));
+            assert_eq!(response, vec!["ACTIVE 0", "TOTAL 0"]);
+        }
+    }
+
+    #[test]
+    fn rekey_retires_the_old_key_after_the_grace_window() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("REKEY {} {}", KEY, NEW_KEY));
+        // Stands in for REKEY_GRACE_SECS elapsing; the integration test waits it out.
+        Server::meta_set(
+            &node.db.lock().unwrap(),
+            "rekey_grace_until",
+            &Server::now_secs().to_string(),
+        )
+        .unwrap();
+        let old = node.run("127.0.0.1:9001", &format!("COUNT {}", KEY));
+        assert_eq!(old, vec!["Authentication failed!"]);
+        let new = node.run("127.0.0.1:9001", &format!("COUNT {}", NEW_KEY));
+        assert_eq!(new, vec!["ACTIVE 0", "TOTAL 0"]);
+        // Once retired, the old key can't rotate the swarm back.
+        let again = node.run("127.0.0.1:9001", &format!("REKEY {} {}", KEY, KEY));
+        assert_eq!(again, vec!["Authentication failed!"]);
+    }
+
+    #[test]
+    fn rekey_survives_a_restart() {
+        let path = env::temp_dir().join(format!("rekey-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let node = TestNode::open(path.to_str().unwrap());
+        node.run("127.0.0.1:9001", &format!("REKEY {} {}", KEY, NEW_KEY));
+        drop(node);
+
+        // Reopening with the old MASTER_KEY keeps the rotated key.
+        let node = TestNode::open(path.to_str().unwrap());
+        let response = node.run("127.0.0.1:9001", &format!("COUNT {}", NEW_KEY));
+        assert_eq!(response, vec!["ACTIVE 0", "TOTAL 0"]);
+        drop(node);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rekey_with_the_wrong_key_changes_nothing() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", &format!("REKEY wrong-key {}", NEW_KEY));
+        assert_eq!(response, vec!["Authentication failed!"]);
+        let new = node.run("127.0.0.1:9001", &format!("COUNT {}", NEW_KEY));
+        assert_eq!(new, vec!["Authentication failed!"]);
+    }
+
+    #[test]
+    fn join_waits_out_a_locked_database() {
+        let path = env::temp_dir().join(format!("busy-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let node = TestNode::open(path.to_str().unwrap());
+        let other = Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            other.execute_batch("COMMIT").unwrap();
+        });
+
+        let response = node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        assert_eq!(response[0], "Swam has been joined!");
+        holder.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn registered_command_is_dispatched_and_listed() {
+        let mut node = TestNode::new();
+        node.registry.register("ECHO", |line, ctx| {
+            Ok(Response::line(&format!("{} from {}", line, ctx.address)))
+        });
+        let response = node.run("127.0.0.1:9001", "ECHO hello");
+        assert_eq!(response, vec!["ECHO hello from 127.0.0.1:9001"]);
+        let verbs = node.run("127.0.0.1:9001", "COMMANDS");
+        assert_eq!(verbs.last().map(|verb| verb.as_str()), Some("ECHO"));
+    }
+
+    #[test]
+    fn changed_master_key_is_refused_unless_accepted() {
+        let node = TestNode::new();
+        let db = node.db.lock().unwrap();
+        let error = Server::seed_key(&db, "another-test-key", false).unwrap_err();
+        assert!(error.to_string().contains("--accept-key-change"));
+        assert!(Server::authenticate(&db, KEY).unwrap());
+        Server::seed_key(&db, "another-test-key", true).unwrap();
+        assert!(Server::authenticate(&db, "another-test-key").unwrap());
+        assert!(!Server::authenticate(&db, KEY).unwrap());
+    }
+
+    #[test]
+    fn count_follows_joins_and_leaves() {
+        let node = TestNode::new();
+        let count = format!("COUNT {}", KEY);
+        assert_eq!(
+            node.run("127.0.0.1:9001", &count),
+            vec!["ACTIVE 0", "TOTAL 0"]
+        );
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        node.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        assert_eq!(
+            node.run("127.0.0.1:9001", &count),
+            vec!["ACTIVE 2", "TOTAL 2"]
+        );
+        node.run("127.0.0.1:9002", "LEAVE");
+        assert_eq!(
+            node.run("127.0.0.1:9001", &count),
+            vec!["ACTIVE 1", "TOTAL 2"]
+        );
+    }
+
+    #[test]
+    fn repl_lists_and_purges_servers() {
+        let node = TestNode::new();
+        node.run("127.0.0.1:9001", &format!("JOIN {}", KEY));
+        node.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        node.run("127.0.0.1:9002", "LEAVE");
+        let mut output = Vec::new();
+        Server::repl(
+            "list\npurge\nstatus\nnope\n".as_bytes(),
+            &mut output,
+            &node.db,
+            &node.connections,
+            true,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[..3],
+            ["1 127.0.0.1:9001", "2 127.0.0.1:9002 (left)", "purged 1"]
+        );
+        assert_eq!(lines[3], "role master");
+        assert_eq!(lines[5], "servers 1");
+        assert_eq!(lines[6], "unknown command 'nope', try help");
+        assert_eq!(node.server_count(), 1);
+    }
+
+    #[test]
+    fn alias_is_dispatched_as_its_verb() {
+        let mut node = TestNode::new();
+        node.registry.alias("hc", "HEALTH").unwrap();
+        node.registry.alias("ev", "EVENTS").unwrap();
+        assert_eq!(node.run("127.0.0.1:9001", "hc"), vec!["200 OK"]);
+        let response = node.run("127.0.0.1:9001", &format!("ev {} --limit 1", KEY));
+        assert_eq!(
+            response,
+            node.run("127.0.0.1:9001", &format!("EVENTS {} --limit 1", KEY))
+        );
+        assert!(node.registry.alias("LIST", "HEALTH").is_err());
+        assert!(node.registry.alias("ls", "NOPE").is_err());
+    }
+
+    #[test]
+    fn taillog_without_log_file_is_reported() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", &format!("TAILLOG {}", KEY));
+        assert_eq!(
+            response,
+            vec!["404 NO_LOG_FILE file logging is not enabled"]
+        );
+    }
+
+    #[test]
+    fn commands_lists_every_verb() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", "COMMANDS");
+        assert_eq!(
+            response,
+            vec![
+                "JOIN",
+                "LEAVE",
+                "LIST",
+                "FILES",
+                "REKEY",
+                "HEALTH",
+                "COMMANDS",
+                "SUBSCRIBE",
+                "USAGE",
+                "EVICT",
+                "CONNSTATS",
+                "EVENTS",
+                "FLUSH",
+                "MASTER",
+                "DELETEMANY",
+                "TAILLOG",
+                "COUNT",
+                "SESSION",
+            ]
+        );
+    }
+
+    #[test]
+    fn subscriber_receives_join_event() {
+        let node = TestNode::new();
+        let subscription = node.send("127.0.0.1:9001", &format!("SUBSCRIBE {}", KEY));
+        assert_eq!(subscription.lines, vec!["200 SUBSCRIBED"]);
+        node.run("127.0.0.1:9002", &format!("JOIN {}", KEY));
+        let received = subscription.stream.unwrap().try_recv().unwrap();
+        assert_eq!(received, "EVENT JOIN 127.0.0.1:9002");
+    }
+
+    #[test]
+    fn unknown_command_is_reported() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", "NOPE");
+        assert_eq!(response, vec!["400 UNKNOWN_COMMAND unknown command 'NOPE'"]);
+    }
+
+    #[test]
+    fn unknown_command_suggests_near_miss() {
+        let node = TestNode::new();
+        let response = node.run("127.0.0.1:9001", "LST");
+        assert_eq!(
+            response,
+            vec!["400 UNKNOWN_COMMAND unknown command 'LST'; did you mean 'LIST'?"]
+        );
+    }
+
+    #[test]
+    fn interpolate_expands_present_variables() {
+        let lookup = |name: &str| (name == "HOST").then(|| "10.0.0.5".to_string());
+        assert_eq!(interpolate("${HOST}", lookup).unwrap(), "10.0.0.5");
+        assert_eq!(
+            interpolate("tcp://${HOST}/x", lookup).unwrap(),
+            "tcp://10.0.0.5/x"
+        );
+        assert_eq!(
+            interpolate("${HOST:-127.0.0.1}", lookup).unwrap(),
+            "10.0.0.5"
+        );
+        assert_eq!(
+            interpolate("no $vars here", lookup).unwrap(),
+            "no $vars here"
+        );
+    }
+
+    #[test]
+    fn interpolate_rejects_absent_variables() {
+        let error = interpolate("${MISSING}", |_| None).unwrap_err();
+        assert_eq!(error, "environment variable MISSING is not set");
+        assert!(interpolate("${MISSING", |_| None).is_err());
+    }
+
+    #[test]
+    fn interpolate_uses_defaults_for_absent_variables() {
+        assert_eq!(interpolate("${PORT:-8777}", |_| None).unwrap(), "8777");
+        assert_eq!(interpolate("${KEY:-}", |_| None).unwrap(), "");
+    }
+
+    #[test]
+    fn http_index_needs_a_token_and_lists_files() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a b.txt".to_string(), 3), ("<x>".to_string(), 1)];
+        let response = Server::http_response("/", None, &node.db, &node.storage).unwrap();
+        assert_eq!(response.status, 401);
+        let target = format!("/index.json?token={}", KEY);
+        let response = Server::http_response(&target, None, &node.db, &node.storage).unwrap();
+        assert_eq!(
+            (response.status, response.content_type),
+            (200, "application/json")
+        );
+        let index: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(index["files"], serde_json::json!(["<x>", "a b.txt"]));
+        let target = format!("/?token={}", KEY);
+        let response = Server::http_response(&target, None, &node.db, &node.storage).unwrap();
+        let html = String::from_utf8(response.body).unwrap();
+        assert!(html.contains("<a href=\"/files/a%20b.txt?token=test-key\">a b.txt</a>"));
+        assert!(html.contains(">&lt;x&gt;</a>"));
+    }
+
+    #[test]
+    fn http_serves_only_listed_files() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a b.txt".to_string(), 3)];
+        let target = format!("/files/a%20b.txt?token={}", KEY);
+        let response = Server::http_response(&target, None, &node.db, &node.storage).unwrap();
+        assert_eq!((response.status, response.body), (200, b"xxx".to_vec()));
+        let target = format!("/files/..%2Fmaster_node.db?token={}", KEY);
+        let response = Server::http_response(&target, None, &node.db, &node.storage).unwrap();
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn http_download_is_not_modified_while_its_etag_matches() {
+        let mut node = TestNode::new();
+        node.storage.files = vec![("a.txt".to_string(), 3)];
+        let target = format!("/files/a.txt?token={}", KEY);
+        let full = Server::http_response(&target, None, &node.db, &node.storage).unwrap();
+        let etag = full.etag.unwrap();
+        assert_eq!(etag, format!("\"{}\"", Server::content_hash(b"xxx")));
+
+        let cached = Server::http_response(&target, Some(&etag), &node.db, &node.storage).unwrap();
+        assert_eq!((cached.status, cached.body), (304, Vec::new()));
+        assert_eq!(cached.etag.as_ref(), Some(&etag));
+        let listed = format!("\"stale\", W/{}", etag);
+        let cached =
+            Server::http_response(&target, Some(&listed), &node.db, &node.storage).unwrap();
+        assert_eq!(cached.status, 304);
+
+        node.storage.files = vec![("a.txt".to_string(), 4)];
+        let changed = Server::http_response(&target, Some(&etag), &node.db, &node.storage).unwrap();
+        assert_eq!((changed.status, changed.body), (200, b"xxxx".to_vec()));
+        assert_ne!(changed.etag, Some(etag));
+    }
+
+    #[test]
+    fn token_bucket_allows_burst_then_spaces_takes() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0, start);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::ZERO);
+        assert_eq!(bucket.take(start), Duration::from_millis(100));
+        // Waiting out the pause pays back the borrowed token and no more.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(bucket.take(later), Duration::from_millis(100));
+        // A long idle refills only up to the burst.
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(bucket.take(idle), Duration::ZERO);
+        assert_eq!(bucket.take(idle), Duration::ZERO);
+        assert!(bucket.take(idle) > Duration::ZERO);
+    }
+
+    #[test]
+    fn json_request_log_parses() {
+        let source: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+        let line = RequestLog::Json
+            .line("JO\"IN", source, Some(409), Duration::from_micros(1500))
+            .unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["method"], "JO\"IN");
+        assert_eq!(entry["source"], "127.0.0.1:9001");
+        assert_eq!(entry["code"], 409);
+        assert_eq!(entry["duration_us"], 1500);
+        assert!(entry["ts"].is_u64());
+
+        let line = RequestLog::Json
+            .line("LIST", source, None, Duration::ZERO)
+            .unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(entry["code"].is_null());
+        assert_eq!(
+            RequestLog::Off.line("LIST", source, None, Duration::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn reply_code_reads_status_prefix() {
+        assert_eq!(
+            reply_code(&["200 FLUSHED wal_frames=0".to_string()]),
+            Some(200)
+        );
+        assert_eq!(reply_code(&["Swam has been joined!".to_string()]), None);
+        assert_eq!(reply_code(&[]), None);
+    }
 }
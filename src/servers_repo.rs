@@ -0,0 +1,212 @@
+// Every query on the `servers` table, returning typed rows instead of tuples picked
+// apart by column index.
+
+use rusqlite::{Connection, OptionalExtension, Result as DBResult, Row};
+use std::net::SocketAddr;
+
+const COLUMNS: &str = "id, ip_address, port, is_active, has_left";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerRecord {
+    pub id: i64,
+    pub ip_address: String,
+    pub port: String,
+    pub is_active: bool,
+    pub has_left: bool,
+}
+
+impl ServerRecord {
+    // Expects the columns in COLUMNS order.
+    fn from_row(row: &Row) -> DBResult<Self> {
+        Ok(ServerRecord {
+            id: row.get(0)?,
+            ip_address: row.get(1)?,
+            port: row.get(2)?,
+            is_active: row.get(3)?,
+            has_left: row.get(4)?,
+        })
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.ip_address, self.port)
+    }
+}
+
+// Registers a node and returns its id.
+pub fn insert(db: &Connection, address: SocketAddr) -> DBResult<i64> {
+    db.execute(
+        "INSERT INTO servers (ip_address, port) VALUES (?1, ?2)",
+        [address.ip().to_string(), address.port().to_string()],
+    )?;
+    Ok(db.last_insert_rowid())
+}
+
+pub fn find_by_id(db: &Connection, id: i64) -> DBResult<Option<ServerRecord>> {
+    db.query_row(
+        &format!("SELECT {} FROM servers WHERE id = ?1", COLUMNS),
+        [id],
+        ServerRecord::from_row,
+    )
+    .optional()
+}
+
+pub fn find_by_address(db: &Connection, address: SocketAddr) -> DBResult<Option<ServerRecord>> {
+    db.query_row(
+        &format!(
+            "SELECT {} FROM servers WHERE ip_address = ?1 AND port = ?2",
+            COLUMNS
+        ),
+        [address.ip().to_string(), address.port().to_string()],
+        ServerRecord::from_row,
+    )
+    .optional()
+}
+
+// Marks a node as gone, whether it left or was evicted. Returns false if no row
+// has that id.
+pub fn mark_left(db: &Connection, id: i64) -> DBResult<bool> {
+    let updated = db.execute(
+        "UPDATE servers SET has_left = true, is_active = false WHERE id = ?1",
+        [id],
+    )?;
+    Ok(updated > 0)
+}
+
+// Nodes that have joined and not left, oldest first.
+pub fn list_active(db: &Connection) -> DBResult<Vec<ServerRecord>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT {} FROM servers WHERE has_left = 0 ORDER BY id",
+        COLUMNS
+    ))?;
+    stmt.query_map([], ServerRecord::from_row)?.collect()
+}
+
+// Every node ever registered, including those that left, oldest first.
+pub fn list_all(db: &Connection) -> DBResult<Vec<ServerRecord>> {
+    let mut stmt = db.prepare(&format!("SELECT {} FROM servers ORDER BY id", COLUMNS))?;
+    stmt.query_map([], ServerRecord::from_row)?.collect()
+}
+
+// How many nodes are active, and how many are registered at all.
+pub fn counts(db: &Connection) -> DBResult<(i64, i64)> {
+    db.query_row(
+        "SELECT COUNT(*) FILTER (WHERE has_left = 0), COUNT(*) FROM servers",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+pub fn set_ip_address(db: &Connection, id: i64, ip_address: &str) -> DBResult<bool> {
+    let updated = db.execute(
+        "UPDATE servers SET ip_address = ?1 WHERE id = ?2",
+        rusqlite::params![ip_address, id],
+    )?;
+    Ok(updated > 0)
+}
+
+// Deletes the nodes that have left and returns how many there were.
+pub fn purge_left(db: &Connection) -> DBResult<usize> {
+    db.execute("DELETE FROM servers WHERE has_left = 1", [])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Connection {
+        crate::Server::open_db(":memory:").unwrap()
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn insert_returns_new_ids() {
+        let db = db();
+        assert_eq!(insert(&db, address(9001)).unwrap(), 1);
+        assert_eq!(insert(&db, address(9002)).unwrap(), 2);
+    }
+
+    #[test]
+    fn find_by_id_returns_the_record() {
+        let db = db();
+        let id = insert(&db, address(9001)).unwrap();
+        let record = find_by_id(&db, id).unwrap().unwrap();
+        assert_eq!(
+            record,
+            ServerRecord {
+                id,
+                ip_address: "127.0.0.1".to_string(),
+                port: "9001".to_string(),
+                is_active: true,
+                has_left: false,
+            }
+        );
+        assert_eq!(record.address(), "127.0.0.1:9001");
+        assert_eq!(find_by_id(&db, id + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn find_by_address_matches_ip_and_port() {
+        let db = db();
+        let id = insert(&db, address(9001)).unwrap();
+        assert_eq!(find_by_address(&db, address(9001)).unwrap().unwrap().id, id);
+        assert_eq!(find_by_address(&db, address(9002)).unwrap(), None);
+    }
+
+    #[test]
+    fn mark_left_flags_the_row() {
+        let db = db();
+        let id = insert(&db, address(9001)).unwrap();
+        assert!(mark_left(&db, id).unwrap());
+        let record = find_by_id(&db, id).unwrap().unwrap();
+        assert!(record.has_left && !record.is_active);
+        assert!(!mark_left(&db, id + 1).unwrap());
+    }
+
+    #[test]
+    fn list_active_skips_nodes_that_left() {
+        let db = db();
+        let first = insert(&db, address(9001)).unwrap();
+        let second = insert(&db, address(9002)).unwrap();
+        insert(&db, address(9003)).unwrap();
+        mark_left(&db, second).unwrap();
+        let active: Vec<i64> = list_active(&db).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(active, vec![first, 3]);
+    }
+
+    #[test]
+    fn list_all_includes_nodes_that_left() {
+        let db = db();
+        let first = insert(&db, address(9001)).unwrap();
+        let second = insert(&db, address(9002)).unwrap();
+        mark_left(&db, first).unwrap();
+        let all: Vec<i64> = list_all(&db).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(all, vec![first, second]);
+    }
+
+    #[test]
+    fn counts_and_purge_left() {
+        let db = db();
+        let first = insert(&db, address(9001)).unwrap();
+        insert(&db, address(9002)).unwrap();
+        assert_eq!(counts(&db).unwrap(), (2, 2));
+        mark_left(&db, first).unwrap();
+        assert_eq!(counts(&db).unwrap(), (1, 2));
+        assert_eq!(purge_left(&db).unwrap(), 1);
+        assert_eq!(counts(&db).unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn set_ip_address_rewrites_the_row() {
+        let db = db();
+        let id = insert(&db, address(9001)).unwrap();
+        assert!(set_ip_address(&db, id, "10.0.0.5").unwrap());
+        assert_eq!(
+            find_by_id(&db, id).unwrap().unwrap().address(),
+            "10.0.0.5:9001"
+        );
+        assert!(!set_ip_address(&db, id + 1, "10.0.0.5").unwrap());
+    }
+}
@@ -0,0 +1,809 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use rusqlite::Connection;
+use socket2::{Domain, Socket, Type};
+
+// A `serve` process running as a master in its own scratch directory.
+struct Node {
+    child: Child,
+    dir: PathBuf,
+    address: String,
+}
+
+impl Node {
+    fn start(name: &str) -> Node {
+        Self::start_with(name, &[])
+    }
+
+    fn start_with(name: &str, envs: &[(&str, &str)]) -> Node {
+        let dir = env::temp_dir().join(format!("swarm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("storage")).unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+            .arg("serve")
+            .arg("--quiet")
+            .current_dir(&dir)
+            .env("MASTER_KEY", "test-key")
+            .env("DB_PATH", ":memory:")
+            .env("SWARM_LOCK_DIR", &dir)
+            .envs(envs.iter().copied())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let address = Self::wait_ready(child.stdout.take().unwrap());
+        Node {
+            child,
+            dir,
+            address,
+        }
+    }
+
+    // Blocks until the server announces `READY <addr>`.
+    fn wait_ready(stdout: ChildStdout) -> String {
+        for line in BufReader::new(stdout).lines() {
+            let line = line.unwrap();
+            if let Some(address) = line.strip_prefix("READY ") {
+                return address.to_string();
+            }
+        }
+        panic!("server exited before it was ready");
+    }
+
+    fn send(&self, line: &str) -> String {
+        let stream = TcpStream::connect(&self.address).unwrap();
+        exchange(stream, line)
+    }
+
+    // Sends `line` from a socket bound to `source`, so the master sees that address.
+    fn send_from(&self, source: &str, line: &str) -> String {
+        let source: SocketAddr = source.parse().unwrap();
+        let master: SocketAddr = self.address.parse().unwrap();
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.bind(&source.into()).unwrap();
+        socket.connect(&master.into()).unwrap();
+        exchange(socket.into(), line)
+    }
+}
+
+fn exchange(mut stream: TcpStream, line: &str) -> String {
+    writeln!(stream, "{}", line).unwrap();
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).unwrap();
+    response.trim().to_string()
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn serve_accepts_connections_once_ready() {
+    let node = Node::start("ready");
+    assert_eq!(node.send("HEALTH"), "200 OK");
+}
+
+#[test]
+fn banner_is_sent_on_connect_when_enabled() {
+    let node = Node::start_with(
+        "banner",
+        &[("BANNER", "true"), ("MOTD", "maintenance at 5")],
+    );
+    let stream = TcpStream::connect(&node.address).unwrap();
+    let mut banner = String::new();
+    BufReader::new(stream).read_line(&mut banner).unwrap();
+    assert!(banner.starts_with("BANNER swarm="));
+    assert!(banner.contains(&format!("version={}", env!("CARGO_PKG_VERSION"))));
+    assert!(banner.trim_end().ends_with("motd=maintenance at 5"));
+}
+
+#[test]
+fn concurrent_joins_from_distinct_addresses_each_get_one_row() {
+    const NODES: usize = 16;
+    let node = Arc::new(Node::start_with("joins", &[("DB_PATH", "swarm.db")]));
+    let barrier = Arc::new(Barrier::new(NODES));
+    let joins: Vec<_> = (0..NODES)
+        .map(|i| {
+            let node = node.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                // Every 127.0.0.0/8 address is local, so each join has its own source.
+                node.send_from(&format!("127.0.0.{}:0", i + 2), "JOIN test-key")
+            })
+        })
+        .collect();
+    for join in joins {
+        assert_eq!(join.join().unwrap(), "Swam has been joined!");
+    }
+
+    let db = Connection::open(node.dir.join("swarm.db")).unwrap();
+    let (rows, distinct): (i64, i64) = db
+        .query_row(
+            "SELECT COUNT(*), COUNT(DISTINCT ip_address || ':' || port) FROM servers",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(rows, NODES as i64);
+    assert_eq!(distinct, NODES as i64);
+}
+
+#[test]
+fn explicit_bind_address_refuses_other_local_addresses() {
+    let node = Node::start_with("bind", &[("BIND_ADDRESS", "127.0.0.1")]);
+    let address: SocketAddr = node.address.parse().unwrap();
+    assert_eq!(address.ip().to_string(), "127.0.0.1");
+    let other = SocketAddr::new([127, 0, 0, 2].into(), address.port());
+    assert!(TcpStream::connect(other).is_err());
+    assert_eq!(node.send("HEALTH"), "200 OK");
+}
+
+#[test]
+fn taillog_returns_recent_log_lines() {
+    let log = env::temp_dir().join(format!("swarm-taillog-{}.log", std::process::id()));
+    let content: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+    fs::write(&log, content).unwrap();
+    let node = Node::start_with("taillog", &[("LOG_FILE", log.to_str().unwrap())]);
+
+    let stream = TcpStream::connect(&node.address).unwrap();
+    let mut stream_writer = stream.try_clone().unwrap();
+    writeln!(stream_writer, "TAILLOG test-key --lines 3").unwrap();
+    let lines: Vec<String> = BufReader::new(stream).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["line 28", "line 29", "line 30", "DONE"]);
+
+    // An absurd count is capped rather than allocated.
+    let stream = TcpStream::connect(&node.address).unwrap();
+    let mut stream_writer = stream.try_clone().unwrap();
+    writeln!(stream_writer, "TAILLOG test-key --lines {}", usize::MAX).unwrap();
+    let lines: Vec<String> = BufReader::new(stream).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines.len(), 31);
+    assert_eq!(node.send("HEALTH"), "200 OK");
+    fs::remove_file(&log).unwrap();
+}
+
+#[test]
+fn session_sends_each_stdin_line() {
+    let node = Node::start("session");
+    // A reply line starting with the terminator must come through intact.
+    fs::write(node.dir.join("storage").join(".notes"), "hi").unwrap();
+    let mut client = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .args(["session", &node.address])
+        .current_dir(&node.dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    client
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"HEALTH\n\nCONNSTATS\nCONNSTATS\nLIST\nFILES\nHEALTH\nNOPE\n")
+        .unwrap();
+    let output = client.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let lines: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    assert_eq!(lines[0], "200 OK");
+    assert_eq!(lines[1], "LIVE 1");
+    // Both CONNSTATS went over the one connection, so nothing new was handled between.
+    assert!(lines[3].starts_with("HANDLED "));
+    assert_eq!(lines[3], lines[7]);
+    // LIST streams its listing, and the session carries on after it.
+    assert_eq!(lines[9], "Collecting data from servers...");
+    assert_eq!(lines[10], "DONE");
+    assert_eq!(lines[11..14], [".notes", "DONE", "200 OK"]);
+    assert_eq!(
+        lines.last().unwrap(),
+        "400 UNKNOWN_COMMAND unknown command 'NOPE'"
+    );
+}
+
+#[test]
+fn second_master_for_a_swarm_refuses_to_start() {
+    let shared = env::temp_dir().join(format!("swarm-split-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&shared);
+    fs::create_dir_all(&shared).unwrap();
+    let db = shared.join("swarm.db").display().to_string();
+    let lock_dir = shared.display().to_string();
+    let envs = [
+        ("DB_PATH", db.as_str()),
+        ("SWARM_LOCK_DIR", lock_dir.as_str()),
+    ];
+    let first = Node::start_with("split-first", &envs);
+
+    let second = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .arg("serve")
+        .current_dir(&first.dir)
+        .env("MASTER_KEY", "test-key")
+        .envs(envs)
+        .output()
+        .unwrap();
+    assert!(!second.status.success());
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains(&format!("already has a master at {}", first.address)));
+    drop(first);
+    let _ = fs::remove_dir_all(&shared);
+}
+
+#[test]
+fn malformed_invocations_name_the_problem() {
+    let dir = env::temp_dir().join(format!("swarm-args-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cases: &[(&[&str], &str)] = &[
+        (&["frobnicate"], "unknown command 'frobnicate'"),
+        (&["join"], "'join' needs <ip_address:port>[,...]"),
+        (
+            &["join", "127.0.0.1:8777", "--forse"],
+            "unknown option '--forse' for 'join'",
+        ),
+        (&["files"], "'files' needs <pattern>"),
+        (
+            &["files", "a*", "b*"],
+            "unexpected argument 'b*' for 'files'",
+        ),
+        (&["evict"], "'evict' needs <node_id|ip_address:port>"),
+        (&["commands"], "'commands' needs <ip_address:port>"),
+        (&["session"], "'session' needs <ip_address:port>"),
+        (
+            &["master", "a:1", "b:2"],
+            "unexpected argument 'b:2' for 'master'",
+        ),
+        (&["leave", "now"], "unexpected argument 'now' for 'leave'"),
+        (&["benchmark"], "'benchmark' needs <ip_address:port>"),
+        (
+            &["benchmark", "127.0.0.1:8777", "--requests", "many"],
+            "--requests needs a number, got 'many'",
+        ),
+        (
+            &["benchmark", "127.0.0.1:8777", "--command"],
+            "--command needs a value",
+        ),
+        (&["snapshot"], "'snapshot' needs <path>"),
+        (&["restore"], "'restore' needs <path>"),
+        (&["serve", "--pid-file"], "--pid-file needs a value"),
+        (
+            &["serve", "--deamon"],
+            "unknown option '--deamon' for 'serve'",
+        ),
+    ];
+    for (args, message) in cases {
+        let output = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+            .args(*args)
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.starts_with(&format!("error: {}\n", message)),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn http_get(address: &str, target: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", target).unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+    response
+}
+
+#[test]
+fn http_index_lists_and_serves_files() {
+    // Grab a free port for the HTTP listener; the server binds it right after.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let node = Node::start_with("http", &[("HTTP_PORT", port.as_str())]);
+    fs::write(node.dir.join("storage").join("notes.txt"), "hello").unwrap();
+    let http = format!("127.0.0.1:{}", port);
+
+    let index = http_get(&http, "/index.json?token=test-key");
+    assert!(index.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(index.ends_with("{\"files\":[\"notes.txt\"]}\n"));
+    let file = http_get(&http, "/files/notes.txt?token=test-key");
+    assert!(file.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(file.ends_with("\r\n\r\nhello"));
+    assert!(http_get(&http, "/index.json?token=wrong").starts_with("HTTP/1.1 401 "));
+}
+
+#[test]
+fn http_download_honours_if_none_match() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let node = Node::start_with("http-etag", &[("HTTP_PORT", port.as_str())]);
+    fs::write(node.dir.join("storage").join("notes.txt"), "hello").unwrap();
+    let http = format!("127.0.0.1:{}", port);
+    let conditional = |etag: &str| {
+        let mut stream = TcpStream::connect(&http).unwrap();
+        write!(
+            stream,
+            "GET /files/notes.txt?token=test-key HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n",
+            etag
+        )
+        .unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        response
+    };
+
+    let file = http_get(&http, "/files/notes.txt?token=test-key");
+    let etag = file
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap()
+        .to_string();
+    let cached = conditional(&etag);
+    assert!(cached.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    assert!(cached.ends_with("\r\n\r\n"));
+
+    fs::write(node.dir.join("storage").join("notes.txt"), "changed").unwrap();
+    let changed = conditional(&etag);
+    assert!(changed.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(changed.ends_with("\r\n\r\nchanged"));
+}
+
+#[test]
+fn http_failures_get_a_response() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let node = Node::start_with(
+        "http-errors",
+        &[("HTTP_PORT", port.as_str()), ("MAX_CONNECTIONS", "1")],
+    );
+    let http = format!("127.0.0.1:{}", port);
+
+    // Storage that can't be listed is an internal error, not a dropped connection.
+    fs::remove_dir_all(node.dir.join("storage")).unwrap();
+    let index = http_get(&http, "/index.json?token=test-key");
+    assert!(index.starts_with("HTTP/1.1 500 "), "{}", index);
+
+    // HTTP requests count against MAX_CONNECTIONS along with command connections.
+    // The 500's handler may not have finished yet, so let it free its slot first.
+    thread::sleep(std::time::Duration::from_millis(100));
+    let mut held = TcpStream::connect(&node.address).unwrap();
+    writeln!(held, "SESSION").unwrap();
+    let mut reply = String::new();
+    BufReader::new(&held).read_line(&mut reply).unwrap();
+    assert_eq!(reply.trim(), "200 OK");
+    // Rejected on accept, before the request is read.
+    let mut busy = String::new();
+    std::io::Read::read_to_string(&mut TcpStream::connect(&http).unwrap(), &mut busy).unwrap();
+    assert!(
+        busy.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{}",
+        busy
+    );
+}
+
+#[test]
+fn accept_rate_limit_spaces_out_a_flood() {
+    let node = Node::start_with(
+        "accept-rate",
+        &[("ACCEPT_RATE", "20"), ("ACCEPT_BURST", "2")],
+    );
+    let started = std::time::Instant::now();
+    let clients: Vec<_> = (0..12)
+        .map(|_| {
+            let address = node.address.clone();
+            thread::spawn(move || exchange(TcpStream::connect(&address).unwrap(), "HEALTH"))
+        })
+        .collect();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), "200 OK");
+    }
+    // Two go through at once, the other ten at 20 per second.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(450));
+}
+
+#[test]
+fn accept_rate_limit_covers_http_too() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let node = Node::start_with(
+        "accept-rate-http",
+        &[
+            ("ACCEPT_RATE", "20"),
+            ("ACCEPT_BURST", "2"),
+            ("HTTP_PORT", port.as_str()),
+        ],
+    );
+    let http = format!("127.0.0.1:{}", port);
+    let started = std::time::Instant::now();
+    let clients: Vec<_> = (0..12)
+        .map(|i| {
+            let address = node.address.clone();
+            let http = http.clone();
+            thread::spawn(move || {
+                if i % 2 == 0 {
+                    exchange(TcpStream::connect(&address).unwrap(), "HEALTH")
+                } else {
+                    http_get(&http, "/index.json?token=test-key")
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        let reply = client.join().unwrap();
+        assert!(
+            reply == "200 OK" || reply.starts_with("HTTP/1.1 200 OK"),
+            "{}",
+            reply
+        );
+    }
+    // One bucket for both listeners: two at once, the other ten at 20 per second.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(450));
+}
+
+#[test]
+fn config_with_an_unset_variable_is_reported_not_panicked() {
+    let dir = env::temp_dir().join(format!("swarm-interp-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let whoami = |config: &str| {
+        fs::write(dir.join("config.txt"), config).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+            .arg("whoami")
+            .current_dir(&dir)
+            .env_remove("SWARM_TEST_UNSET")
+            .output()
+            .unwrap()
+    };
+
+    let output = whoami("master_ip_address=${SWARM_TEST_UNSET}\nmaster_port=8777\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("environment variable SWARM_TEST_UNSET is not set"));
+
+    // Keys are taken verbatim, so one that happens to contain `${` still loads.
+    let output = whoami("master_ip_address=127.0.0.1\nmaster_port=8777\nmaster_key=a${b\n");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Master: 127.0.0.1:8777"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn subscribers_do_not_use_up_connection_slots() {
+    let node = Node::start_with("subscribers", &[("MAX_CONNECTIONS", "2")]);
+    let subscriptions: Vec<_> = (0..3)
+        .map(|_| {
+            let mut stream = TcpStream::connect(&node.address).unwrap();
+            writeln!(stream, "SUBSCRIBE test-key").unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            assert_eq!(reply.trim(), "200 SUBSCRIBED");
+            reader
+        })
+        .collect();
+    assert_eq!(node.send("HEALTH"), "200 OK");
+    drop(subscriptions);
+}
+
+#[test]
+fn slave_reconnects_after_master_restarts() {
+    // Stands in for the master: answers one SUBSCRIBE and returns the reader.
+    fn accept_subscription(listener: &std::net::TcpListener) -> BufReader<TcpStream> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut command = String::new();
+        reader.read_line(&mut command).unwrap();
+        assert_eq!(command.trim(), "SUBSCRIBE test-key");
+        writeln!(reader.get_mut(), "200 SUBSCRIBED").unwrap();
+        reader
+    }
+
+    let dir = env::temp_dir().join(format!("swarm-relink-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("storage")).unwrap();
+    let master = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let master_address = master.local_addr().unwrap();
+    fs::write(
+        dir.join("config.txt"),
+        format!(
+            "master_ip_address=127.0.0.1\nmaster_port={}\nslave_port=0\nmaster_key=test-key\n",
+            master_address.port()
+        ),
+    )
+    .unwrap();
+    let mut slave = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .arg("serve")
+        .current_dir(&dir)
+        .env("DB_PATH", ":memory:")
+        .env("SWARM_LOCK_DIR", "off")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Kill the master: the link and the listening socket both go away.
+    drop(accept_subscription(&master));
+    drop(master);
+    thread::sleep(std::time::Duration::from_millis(200));
+    // Restart it on the same port. The slave retries with backoff and links up again.
+    let master = std::net::TcpListener::bind(master_address).unwrap();
+    let relinked = accept_subscription(&master);
+
+    drop(relinked);
+    let _ = slave.kill();
+    let _ = slave.wait();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn invalid_settings_are_reported_not_defaulted_silently() {
+    let dir = env::temp_dir().join(format!("swarm-settings-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .arg("dump-config")
+        .current_dir(&dir)
+        .env("MAX_CONNECTIONS", "1O0")
+        .env("REQUEST_LOG", "jsn")
+        .env("BIND_ADDRESS", "10.0.0.5x")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Ignoring MAX_CONNECTIONS=\"1O0\""));
+    assert!(stderr.contains("unknown request log format 'jsn'"));
+    assert!(stderr.contains("Ignoring BIND_ADDRESS=\"10.0.0.5x\""));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let max_connections = stdout
+        .lines()
+        .find(|line| line.starts_with("max_connections"))
+        .unwrap();
+    assert!(max_connections.ends_with("(invalid)"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn read_only_key_is_forbidden_destructive_commands() {
+    use sha2::{Digest, Sha256};
+    let hash = |key: &str| -> String {
+        Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    };
+    let keys = env::temp_dir().join(format!("swarm-keys-{}.txt", std::process::id()));
+    fs::write(&keys, format!("{} read\n", hash("reader-key"))).unwrap();
+    let node = Node::start_with("roles", &[("KEYS_FILE", keys.to_str().unwrap())]);
+
+    assert_eq!(
+        node.send("EVICT reader-key 127.0.0.1:9001"),
+        "403 FORBIDDEN"
+    );
+    assert_eq!(
+        node.send("DELETEMANY reader-key * --confirm"),
+        "403 FORBIDDEN"
+    );
+    assert_eq!(node.send("JOIN reader-key"), "403 FORBIDDEN");
+    assert_eq!(node.send("COUNT reader-key"), "ACTIVE 0");
+    assert_eq!(
+        node.send("EVICT stranger 127.0.0.1:9001"),
+        "Authentication failed!"
+    );
+    fs::remove_file(&keys).unwrap();
+}
+
+#[test]
+fn connections_over_the_limit_get_503_busy_promptly() {
+    let node = Node::start_with("busy", &[("MAX_CONNECTIONS", "2")]);
+    // Holding connections open without a command keeps their handlers busy.
+    let held: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(&node.address).unwrap())
+        .collect();
+    // The handlers count as live once accepted; give the accept loop a moment.
+    thread::sleep(std::time::Duration::from_millis(100));
+    for _ in 0..3 {
+        let started = std::time::Instant::now();
+        let stream = TcpStream::connect(&node.address).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert_eq!(reply.trim(), "503 BUSY");
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+    drop(held);
+}
+
+#[test]
+fn idle_clients_are_cut_off_and_free_their_slot() {
+    let node = Node::start_with(
+        "idle",
+        &[("MAX_CONNECTIONS", "1"), ("IDLE_TIMEOUT_SECS", "1")],
+    );
+    let started = std::time::Instant::now();
+    let mut idle = BufReader::new(TcpStream::connect(&node.address).unwrap());
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(node.send("HEALTH"), "503 BUSY");
+
+    // The server hangs up on the silent client, which then sees end of stream.
+    let mut reply = String::new();
+    assert_eq!(idle.read_line(&mut reply).unwrap(), 0);
+    assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+    assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(node.send("HEALTH"), "200 OK");
+
+    // An idle session is closed the same way, once the HEALTH above has let go.
+    thread::sleep(std::time::Duration::from_millis(100));
+    let mut session = TcpStream::connect(&node.address).unwrap();
+    writeln!(session, "SESSION").unwrap();
+    let mut session = BufReader::new(session);
+    reply.clear();
+    session.read_line(&mut reply).unwrap();
+    assert_eq!(reply.trim(), "200 OK");
+    reply.clear();
+    assert_eq!(session.read_line(&mut reply).unwrap(), 0);
+}
+
+#[test]
+fn slave_leaves_through_the_cli() {
+    let node = Node::start("leave");
+    let dir = env::temp_dir().join(format!("swarm-leave-slave-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+            .args(args)
+            .current_dir(&dir)
+            .env("MASTER_KEY", "test-key")
+            .env("DB_PATH", ":memory:")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    };
+
+    run(&["join", &node.address]);
+    assert!(dir.join("config.txt").exists());
+    assert_eq!(node.send("COUNT test-key"), "ACTIVE 1");
+    // The LEAVE comes from a new source port; the master still finds the joined row.
+    run(&["leave"]);
+    assert!(!dir.join("config.txt").exists());
+    assert_eq!(node.send("COUNT test-key"), "ACTIVE 0");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rekey_reaches_the_slave_and_retires_the_old_key() {
+    let node = Node::start_with("rekey", &[("REKEY_GRACE_SECS", "2")]);
+    let dir = env::temp_dir().join(format!("swarm-rekey-slave-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("storage")).unwrap();
+    let join = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .args(["join", &node.address])
+        .current_dir(&dir)
+        .env("MASTER_KEY", "test-key")
+        .env("DB_PATH", ":memory:")
+        .output()
+        .unwrap();
+    assert!(join.status.success());
+    let mut slave = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+        .args(["serve", "--quiet"])
+        .current_dir(&dir)
+        .env("MASTER_KEY", "test-key")
+        .env("DB_PATH", ":memory:")
+        .env("SWARM_LOCK_DIR", "off")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let slave_address = Node::wait_ready(slave.stdout.take().unwrap());
+
+    assert_eq!(
+        node.send("REKEY test-key rotated-key-0123456789"),
+        "200 REKEYED 1/1"
+    );
+    // The slave took the new key, and wrote it where it would find it after a restart.
+    let on_slave = exchange(
+        TcpStream::connect(&slave_address).unwrap(),
+        "COUNT rotated-key-0123456789",
+    );
+    assert_eq!(on_slave, "ACTIVE 0");
+    let config = fs::read_to_string(dir.join("config.txt")).unwrap();
+    assert!(config.contains("master_key=rotated-key-0123456789"));
+
+    // Both keys work on the master until the grace window closes.
+    assert_eq!(node.send("COUNT test-key"), "ACTIVE 1");
+    // The window is kept in whole seconds, so the checks above have at least a second.
+    thread::sleep(std::time::Duration::from_millis(2100));
+    assert_eq!(node.send("COUNT test-key"), "Authentication failed!");
+    assert_eq!(node.send("COUNT rotated-key-0123456789"), "ACTIVE 1");
+
+    let _ = slave.kill();
+    let _ = slave.wait();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn join_through_a_bracketed_ipv6_master_writes_a_usable_config() {
+    let node = Node::start_with("join-v6", &[("BIND_ADDRESS", "::1")]);
+    assert!(node.address.starts_with("[::1]:"));
+    let port = node.address.rsplit_once(':').unwrap().1.to_string();
+    let dir = env::temp_dir().join(format!("swarm-join-v6-slave-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"))
+            .args(args)
+            .current_dir(&dir)
+            .env("MASTER_KEY", "test-key")
+            .env("DB_PATH", ":memory:")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    };
+
+    run(&["join", &format!("127.0.0.1:1,{}", node.address)]);
+    let config = fs::read_to_string(dir.join("config.txt")).unwrap();
+    assert!(config.contains("master_ip_address=::1\n"), "{}", config);
+    assert!(
+        config.contains(&format!("master_port={}\n", port)),
+        "{}",
+        config
+    );
+    // The config is good enough to reach the master again.
+    run(&["leave"]);
+    assert!(!dir.join("config.txt").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn connstats_live_count_returns_to_zero_after_real_requests() {
+    let node = Node::start_with("connstats", &[("LOG_FILE", "/nonexistent/swarm.log")]);
+    // A normal command, one whose handler fails, and a client that hangs up unasked.
+    assert_eq!(node.send("HEALTH"), "200 OK");
+    assert_eq!(node.send("TAILLOG test-key"), "");
+    drop(TcpStream::connect(&node.address).unwrap());
+
+    // Slots are released as each handler thread finishes, just after it closes.
+    let mut stats = Vec::new();
+    for _ in 0..50 {
+        let mut stream = TcpStream::connect(&node.address).unwrap();
+        writeln!(stream, "CONNSTATS").unwrap();
+        stats = BufReader::new(stream)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        if stats[0] == "LIVE 1" {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(20));
+    }
+    // The CONNSTATS connection itself is the only live one.
+    assert_eq!(stats[0], "LIVE 1");
+    assert!(stats[2].starts_with("HANDLED "));
+    assert_eq!(stats[3], "SUBSCRIBERS 0");
+}